@@ -0,0 +1,172 @@
+//! Finding the crates affected by changes in git.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::git;
+use crate::types::{Context, CrateId};
+
+/// Crates changed since `since`, and the crates depending on them.
+#[derive(Debug, Clone)]
+pub struct Affected {
+    /// Crates containing a changed file, or inheriting from a changed workspace manifest.
+    pub changed: BTreeSet<CrateId>,
+    /// The changed crates and all their recursive dependents.
+    pub affected: BTreeSet<CrateId>,
+}
+
+/// Find the crates affected by changes since the working tree diverged from the git ref `since`.
+pub fn affected_since(ctx: &Context, since: &str) -> Result<Affected> {
+    let files = git::changed_files(&ctx.root, since)?;
+    Ok(affected(ctx, &files))
+}
+
+/// Find the crates affected by changes to `files`.
+pub fn affected(ctx: &Context, files: &[PathBuf]) -> Affected {
+    let changed = changed_crates(ctx, files);
+    let affected = ctx.recursive_dependents(changed.iter()).collect();
+    Affected { changed, affected }
+}
+
+/// Map `files` to the crates they belong to.
+///
+/// A file belongs to the crate with the longest path containing it, so files of nested crates
+/// don't count for the outer crate. A changed workspace manifest affects all crates inheriting
+/// from it.
+pub fn changed_crates(ctx: &Context, files: &[PathBuf]) -> BTreeSet<CrateId> {
+    // Changed files are below git's canonical top level, while crate paths are below the
+    // discovery roots, which may contain symlinks or `..` (e.g. `roots = ["../other"]`)
+    let crate_dirs: Vec<(PathBuf, &CrateId)> = ctx
+        .crates
+        .iter()
+        .map(|(name, krate)| (canonical(&krate.path), name))
+        .collect();
+
+    let mut changed = BTreeSet::new();
+    for file in files {
+        if let Some((_, name)) = crate_dirs
+            .iter()
+            .filter(|(dir, _)| file.starts_with(dir))
+            .max_by_key(|(dir, _)| dir.components().count())
+        {
+            changed.insert((*name).clone());
+        }
+
+        if file.file_name().is_some_and(|name| name == "Cargo.toml")
+            && let Some(dir) = file.parent()
+        {
+            changed.extend(
+                ctx.crates
+                    .values()
+                    .filter(|krate| {
+                        krate
+                            .workspace
+                            .as_ref()
+                            .is_some_and(|workspace| canonical(workspace) == dir)
+                    })
+                    .map(|krate| krate.name.clone()),
+            );
+        }
+    }
+    changed
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TempDir};
+    use crate::types::DependencyKind::{Dev, Normal};
+
+    /// A workspace with `embassy-a` inheriting from the root manifest, `embassy-b` with the
+    /// nested `embassy-b-macros`, and `embassy-d` depending on `embassy-c` depending on
+    /// `embassy-b`.
+    fn context(tmp: &TempDir) -> Context {
+        tmp.write("Cargo.toml", "[workspace]\n");
+        let root = tmp.0.canonicalize().unwrap();
+        let mut ctx = testutil::context(
+            &[
+                "embassy-a",
+                "embassy-b",
+                "embassy-b-macros",
+                "embassy-c",
+                "embassy-d",
+            ],
+            &[
+                ("embassy-c", "embassy-b", Normal),
+                ("embassy-d", "embassy-c", Dev),
+            ],
+        );
+        for krate in ctx.crates.values_mut() {
+            let dir = match krate.name.as_str() {
+                "embassy-b-macros" => "embassy-b/macros",
+                name => name,
+            };
+            tmp.write(format!("{dir}/Cargo.toml"), "");
+            krate.path = root.join(dir);
+        }
+        ctx.crates.get_mut("embassy-a").unwrap().workspace = Some(root.clone());
+        ctx.root = root;
+        ctx
+    }
+
+    fn changed(ctx: &Context, files: &[&str]) -> Vec<String> {
+        let files: Vec<_> = files.iter().map(|file| ctx.root.join(file)).collect();
+        changed_crates(ctx, &files).into_iter().collect()
+    }
+
+    #[test]
+    fn nested_crate_takes_its_files() {
+        let tmp = TempDir::new("affected-nested");
+        let ctx = context(&tmp);
+        assert_eq!(
+            changed(&ctx, &["embassy-b/macros/src/lib.rs"]),
+            ["embassy-b-macros"]
+        );
+        assert_eq!(changed(&ctx, &["embassy-b/src/lib.rs"]), ["embassy-b"]);
+        // Only a common prefix of the name, not of the path
+        assert_eq!(
+            changed(&ctx, &["embassy-b-macros/src/lib.rs"]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn workspace_manifest_changes_inheriting_crates() {
+        let tmp = TempDir::new("affected-workspace");
+        let ctx = context(&tmp);
+        assert_eq!(changed(&ctx, &["Cargo.toml"]), ["embassy-a"]);
+        // A crate's own manifest only changes the crate
+        assert_eq!(changed(&ctx, &["embassy-c/Cargo.toml"]), ["embassy-c"]);
+    }
+
+    #[test]
+    fn files_outside_of_crates_change_nothing() {
+        let tmp = TempDir::new("affected-outside");
+        let ctx = context(&tmp);
+        assert_eq!(
+            changed(&ctx, &["README.md", ".github/ci.yml", "Cargo.lock"]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn dependents_are_affected() {
+        let tmp = TempDir::new("affected-dependents");
+        let ctx = context(&tmp);
+        let affected = affected(&ctx, &[ctx.root.join("embassy-b/src/lib.rs")]);
+        assert_eq!(
+            affected.changed.into_iter().collect::<Vec<_>>(),
+            ["embassy-b"]
+        );
+        assert_eq!(
+            affected.affected.into_iter().collect::<Vec<_>>(),
+            ["embassy-b", "embassy-c", "embassy-d"]
+        );
+    }
+}
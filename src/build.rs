@@ -0,0 +1,502 @@
+//! Planning and running `cargo batch` invocations for the build configs of crates.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cargo::{self, OutputLine};
+use crate::diagnostics::{CargoMessage, parse_message};
+use crate::types::{BuildConfig, Crate};
+
+#[derive(Debug, Clone, Copy)]
+pub enum BuildCommand {
+    Build,
+    Check,
+}
+
+/// The settings that configs must share to be built by the same `cargo batch` invocation.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BuildConfigBatch {
+    pub env: BTreeMap<String, String>,
+    pub build_std: Vec<String>,
+}
+
+/// A single cargo command within a batch, building one config of one crate.
+#[derive(Debug, Clone)]
+pub struct BuildStep<'a> {
+    /// Identifies the config across runs, see [`step_id`].
+    pub id: String,
+    pub krate: &'a Crate,
+    pub config: &'a BuildConfig,
+    /// Arguments to cargo, e.g. `build --release --manifest-path=...`.
+    pub args: Vec<String>,
+}
+
+/// A `cargo batch` invocation building all configs with the same [`BuildConfigBatch`].
+#[derive(Debug, Clone)]
+pub struct BuildBatch<'a> {
+    pub config: BuildConfigBatch,
+    pub steps: Vec<BuildStep<'a>>,
+}
+
+impl BuildBatch<'_> {
+    /// Arguments to cargo, with steps separated by `---`.
+    pub fn args(&self) -> Vec<String> {
+        let mut batch_args = vec!["batch".to_string()];
+        if !self.config.build_std.is_empty() {
+            batch_args.push(format!("-Zbuild-std={}", self.config.build_std.join(",")));
+        }
+        for step in &self.steps {
+            batch_args.push("---".to_string());
+            batch_args.extend(step.args.iter().cloned());
+        }
+        batch_args
+    }
+
+    /// Arguments to run the step at `index` on its own, outside of `cargo batch`.
+    pub fn step_args(&self, index: usize) -> Vec<String> {
+        let mut args = self.steps[index].args.clone();
+        if !self.config.build_std.is_empty() {
+            args.push(format!("-Zbuild-std={}", self.config.build_std.join(",")));
+        }
+        args
+    }
+
+    /// Environment to run the batch with. `RUSTFLAGS` is appended to the current environment's.
+    pub fn env(&self) -> BTreeMap<String, String> {
+        let mut final_env = self.config.env.clone();
+        if let Some(config_rustflags) = final_env.get("RUSTFLAGS")
+            && let Ok(existing_rustflags) = std::env::var("RUSTFLAGS")
+            && !existing_rustflags.is_empty()
+        {
+            final_env.insert(
+                "RUSTFLAGS".to_string(),
+                format!("{} {}", existing_rustflags, config_rustflags),
+            );
+        }
+        final_env
+    }
+
+    /// Steps that a diagnostic of `package` belongs to, given only the batch it came from.
+    ///
+    /// `cargo batch` doesn't tell its steps apart in its output, so these are all steps building
+    /// the package. A package that no step builds is a dependency of the batch's crates, and its
+    /// diagnostics belong to every step.
+    pub fn steps_of(&self, package: &str) -> Vec<&BuildStep<'_>> {
+        let steps: Vec<_> = self
+            .steps
+            .iter()
+            .filter(|step| step.krate.name == package)
+            .collect();
+        if steps.is_empty() {
+            self.steps.iter().collect()
+        } else {
+            steps
+        }
+    }
+}
+
+/// Plan the batches building all configs of `crates` in `group`.
+///
+/// If `group` is `None` only configs without a group are built.
+pub fn plan<'a>(
+    crates: impl IntoIterator<Item = &'a Crate>,
+    group: Option<&str>,
+    build_command: BuildCommand,
+) -> Vec<BuildBatch<'a>> {
+    let mut batch_groups: BTreeMap<BuildConfigBatch, Vec<BuildStep<'a>>> = BTreeMap::new();
+
+    for krate in crates {
+        let mut ids = BTreeSet::new();
+        for config in &krate.configs {
+            if config.group.as_deref() != group {
+                continue;
+            }
+
+            let mut id = step_id(krate, config);
+            if !ids.insert(id.clone()) {
+                // Configs only differing in env or build-std, number them in manifest order
+                id = (2..)
+                    .map(|n| format!("{}#{}", id, n))
+                    .find(|id| !ids.contains(id))
+                    .unwrap();
+                ids.insert(id.clone());
+            }
+
+            let batch_key = BuildConfigBatch {
+                env: config.env.clone(),
+                build_std: config.build_std.clone(),
+            };
+
+            batch_groups.entry(batch_key).or_default().push(BuildStep {
+                id,
+                krate,
+                config,
+                args: step_args(krate, config, build_command),
+            });
+        }
+    }
+
+    batch_groups
+        .into_iter()
+        .map(|(config, steps)| BuildBatch { config, steps })
+        .collect()
+}
+
+/// Identifier of a config of a crate, `crate@target[features]`, e.g. `embassy-rp@thumbv6m-none-eabi[rp2040]`.
+///
+/// Configs without a target use `host`, and `[features]` is left out without features. [`plan`]
+/// appends `#2`, `#3`, ... to configs of the same crate that would otherwise get the same id.
+pub fn step_id(krate: &Crate, config: &BuildConfig) -> String {
+    let mut id = format!(
+        "{}@{}",
+        krate.name,
+        config.target.as_deref().unwrap_or("host")
+    );
+    if !config.features.is_empty() {
+        id.push_str(&format!("[{}]", config.features.join(",")));
+    }
+    id
+}
+
+fn step_args(krate: &Crate, config: &BuildConfig, build_command: BuildCommand) -> Vec<String> {
+    let manifest_path = format!("{}/Cargo.toml", krate.path.to_string_lossy());
+    let mut args = match build_command {
+        BuildCommand::Build => vec![
+            "build".to_string(),
+            "--release".to_string(),
+            format!("--manifest-path={}", manifest_path),
+        ],
+        BuildCommand::Check => vec![
+            "check".to_string(),
+            format!("--manifest-path={}", manifest_path),
+        ],
+    };
+
+    // Diagnostics are collected from the JSON messages, see `crate::diagnostics`. Their colors
+    // are stripped when printing if the output isn't a terminal, so the plan is always the same.
+    args.push("--message-format=json-diagnostic-rendered-ansi".to_string());
+    if let Some(ref target) = config.target {
+        args.push(format!("--target={}", target));
+    }
+    if !config.features.is_empty() {
+        args.push(format!("--features={}", config.features.join(",")));
+    }
+    if matches!(build_command, BuildCommand::Build)
+        && let Some(ref artifact_dir) = config.artifact_dir
+    {
+        args.push(format!("--artifact-dir={}", artifact_dir));
+    }
+    args
+}
+
+/// Options for [`run_batches`].
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Number of batches to run at the same time. With more than one, each batch gets its own
+    /// target directory below `CARGO_TARGET_DIR` or `<root>/target` so they don't block each
+    /// other, unless the batch sets `CARGO_TARGET_DIR` itself.
+    pub jobs: usize,
+    /// Keep starting batches after one failed, and build the steps of failed batches one by one
+    /// to find out which of them fail.
+    pub keep_going: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            jobs: 1,
+            keep_going: false,
+        }
+    }
+}
+
+impl RunOptions {
+    /// Environment to run the batch at `index` of the plan with, see [`BuildBatch::env`].
+    pub fn env(&self, batch: &BuildBatch, index: usize, root: &Path) -> BTreeMap<String, String> {
+        let mut env = batch.env();
+        if self.jobs > 1 && !env.contains_key("CARGO_TARGET_DIR") {
+            let target_dir = std::env::var_os("CARGO_TARGET_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| root.join("target"));
+            env.insert(
+                "CARGO_TARGET_DIR".to_string(),
+                target_dir
+                    .join(format!("batch-{}", index))
+                    .display()
+                    .to_string(),
+            );
+        }
+        env
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchStatus {
+    Passed,
+    /// The batch failed with the given error.
+    Failed(String),
+    /// The batch wasn't started because another one failed.
+    Skipped,
+}
+
+/// Outcome of one step of a failed batch, built on its own with [`RunOptions::keep_going`].
+#[derive(Debug, Clone)]
+pub struct StepResult {
+    pub status: BatchStatus,
+    pub duration: Duration,
+    /// Everything the step printed to stderr.
+    pub stderr: String,
+    /// The compiler errors of the step, as rendered by the compiler.
+    pub errors: String,
+}
+
+/// Outcome of one batch run by [`run_batches`].
+#[derive(Debug, Clone)]
+pub struct BatchResult {
+    pub status: BatchStatus,
+    pub duration: Duration,
+    /// Everything the batch printed to stderr.
+    pub stderr: String,
+    /// The compiler errors of the batch, as rendered by the compiler. With
+    /// `--message-format=json` they are printed to stdout instead of stderr.
+    pub errors: String,
+    /// Result of each step if the batch failed and its steps were built one by one, empty
+    /// otherwise.
+    pub steps: Vec<StepResult>,
+}
+
+/// Progress reported by [`run_batches`], identifying batches and their steps by index.
+#[derive(Debug)]
+pub enum BatchEvent<'e> {
+    Started(usize),
+    Output(usize, &'e OutputLine),
+    /// A step of a failed batch is built on its own.
+    StepStarted(usize, usize),
+    StepFinished(usize, usize, &'e StepResult),
+    Finished(usize, &'e BatchResult),
+}
+
+/// Run `batches` in the repository at `root`, stopping at the first failure unless
+/// [`RunOptions::keep_going`] is set.
+///
+/// `on_event` is called from the calling thread as batches start, print output and finish.
+/// Returns the result of every batch, in the same order.
+pub fn run_batches(
+    batches: &[BuildBatch],
+    root: &Path,
+    options: &RunOptions,
+    mut on_event: impl FnMut(BatchEvent),
+) -> Vec<BatchResult> {
+    let mut results = vec![
+        BatchResult {
+            status: BatchStatus::Skipped,
+            duration: Duration::ZERO,
+            stderr: String::new(),
+            errors: String::new(),
+            steps: Vec::new(),
+        };
+        batches.len()
+    ];
+
+    enum Message {
+        Started,
+        Output(OutputLine),
+        StepStarted(usize),
+        StepFinished(usize, StepResult),
+        Finished(BatchResult),
+    }
+
+    let next = &AtomicUsize::new(0);
+    let failed = &AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|s| {
+        for _ in 0..options.jobs.clamp(1, batches.len().max(1)) {
+            let tx = tx.clone();
+            s.spawn(move || {
+                while !failed.load(Ordering::SeqCst) {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(batch) = batches.get(i) else {
+                        break;
+                    };
+                    let env = options.env(batch, i, root);
+
+                    let _ = tx.send((i, Message::Started));
+                    let (status, duration, stderr, errors) =
+                        run_cargo(&batch.args(), root, &env, |line| {
+                            let _ = tx.send((i, Message::Output(line)));
+                        });
+                    let mut steps = Vec::new();
+                    if status != BatchStatus::Passed {
+                        if options.keep_going {
+                            // cargo batch stops at the first failing step, build each one to
+                            // find all failures
+                            for j in 0..batch.steps.len() {
+                                let _ = tx.send((i, Message::StepStarted(j)));
+                                let (status, duration, stderr, errors) =
+                                    run_cargo(&batch.step_args(j), root, &env, |line| {
+                                        let _ = tx.send((i, Message::Output(line)));
+                                    });
+                                let result = StepResult {
+                                    status,
+                                    duration,
+                                    stderr,
+                                    errors,
+                                };
+                                let _ = tx.send((i, Message::StepFinished(j, result.clone())));
+                                steps.push(result);
+                            }
+                        } else {
+                            failed.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    let result = BatchResult {
+                        status,
+                        duration,
+                        stderr,
+                        errors,
+                        steps,
+                    };
+                    let _ = tx.send((i, Message::Finished(result)));
+                }
+            });
+        }
+        drop(tx);
+
+        for (i, message) in rx {
+            match message {
+                Message::Started => on_event(BatchEvent::Started(i)),
+                Message::Output(line) => on_event(BatchEvent::Output(i, &line)),
+                Message::StepStarted(j) => on_event(BatchEvent::StepStarted(i, j)),
+                Message::StepFinished(j, result) => {
+                    on_event(BatchEvent::StepFinished(i, j, &result))
+                }
+                Message::Finished(result) => {
+                    on_event(BatchEvent::Finished(i, &result));
+                    results[i] = result;
+                }
+            }
+        }
+    });
+    results
+}
+
+/// Run cargo with `args`, passing on its output. Returns its status, duration, stderr and the
+/// rendered compiler errors.
+fn run_cargo(
+    args: &[String],
+    root: &Path,
+    env: &BTreeMap<String, String>,
+    mut on_line: impl FnMut(OutputLine),
+) -> (BatchStatus, Duration, String, String) {
+    let start = Instant::now();
+    let mut stderr = String::new();
+    let mut errors = String::new();
+    let result = cargo::run_with_env_streaming(args, root, env, |line| {
+        match &line {
+            OutputLine::Stdout(line) => collect_error(line, root, &mut errors),
+            OutputLine::Stderr(line) => {
+                stderr.push_str(line);
+                stderr.push('\n');
+            }
+        }
+        on_line(line);
+    });
+    let status = match result {
+        Ok(()) => BatchStatus::Passed,
+        Err(e) => BatchStatus::Failed(e.to_string()),
+    };
+    (status, start.elapsed(), stderr, errors)
+}
+
+/// Append the rendered diagnostic to `errors` if `line` is a compiler error message.
+fn collect_error(line: &str, root: &Path, errors: &mut String) {
+    if let Some(CargoMessage::Diagnostic(diagnostic)) = parse_message(line, root)
+        && diagnostic.is_error()
+        && let Some(rendered) = diagnostic.rendered
+    {
+        errors.push_str(&rendered);
+        if !rendered.ends_with('\n') {
+            errors.push('\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn collect_errors() {
+        let root = Path::new("/repo");
+        let message = |level: &str, rendered: &str| {
+            serde_json::json!({
+                "reason": "compiler-message",
+                "package_id": "path+file:///repo/embassy-a#0.1.0",
+                "manifest_path": "/repo/embassy-a/Cargo.toml",
+                "message": {
+                    "level": level,
+                    "code": null,
+                    "message": "msg",
+                    "spans": [],
+                    "rendered": rendered,
+                },
+            })
+            .to_string()
+        };
+
+        let mut errors = String::new();
+        collect_error(&message("warning", "warning: unused\n"), root, &mut errors);
+        collect_error("   Compiling embassy-a v0.1.0", root, &mut errors);
+        collect_error(
+            r#"{"reason":"build-finished","success":false}"#,
+            root,
+            &mut errors,
+        );
+        assert_eq!(errors, "");
+
+        collect_error(
+            &message("error", "error[E0308]: mismatched types\n"),
+            root,
+            &mut errors,
+        );
+        collect_error(&message("error", "error: aborting"), root, &mut errors);
+        assert_eq!(errors, "error[E0308]: mismatched types\nerror: aborting\n");
+    }
+
+    #[test]
+    fn steps_of_package() {
+        let mut a = testutil::krate("embassy-a", &[]);
+        a.configs = vec![
+            BuildConfig::default(),
+            BuildConfig {
+                features: vec!["f".to_string()],
+                ..Default::default()
+            },
+        ];
+        let mut b = testutil::krate("embassy-b", &[]);
+        b.configs = vec![BuildConfig::default()];
+        let crates = [a, b];
+        let batches = plan(&crates, None, BuildCommand::Build);
+        assert_eq!(batches.len(), 1);
+
+        let ids = |package: &str| -> Vec<String> {
+            batches[0]
+                .steps_of(package)
+                .iter()
+                .map(|step| step.id.clone())
+                .collect()
+        };
+        assert_eq!(ids("embassy-a"), ["embassy-a@host", "embassy-a@host[f]"]);
+        assert_eq!(ids("embassy-b"), ["embassy-b@host"]);
+        // A dependency built for the whole batch
+        assert_eq!(
+            ids("embassy-c"),
+            ["embassy-a@host", "embassy-a@host[f]", "embassy-b@host"]
+        );
+    }
+}
@@ -5,77 +5,202 @@ use crate::types::{Context, *};
 use anyhow::{Result, anyhow};
 use toml_edit::{DocumentMut, Item, Value};
 
+/// Set the version of crate `name` and update the requirements of all crates depending on it.
+///
+/// Progress is reported through `log`.
 pub fn bump(ctx: &mut Context, name: &CrateId, new_version: &str) -> Result<(), anyhow::Error> {
-    let c = ctx.crates.get_mut(name).unwrap();
-    let old_version = c.version.clone();
+    let c = ctx
+        .crates
+        .get_mut(name)
+        .ok_or_else(|| anyhow!("Crate '{}' not found", name))?;
     c.version = new_version.to_string();
 
-    update_crate(c, new_version)?;
-    if let Some(reverse_deps) = ctx.reverse_deps.get(name) {
-        for dep in reverse_deps {
-            println!("Updating {name}-{old_version} -> {new_version} for {dep}");
-            update_deps(&ctx.crates[dep], name, new_version)?;
+    let mut bumped = vec![name.clone()];
+    if update_crate(c)? {
+        // The version lives in the workspace root, so every member inheriting it moves too.
+        let workspace = c.workspace.clone();
+        for other in ctx.crates.values_mut() {
+            if other.name != *name && other.workspace == workspace && inherits_version(other)? {
+                log::info!(
+                    "Updating {}-{} -> {new_version} (inherited from workspace)",
+                    other.name,
+                    other.version
+                );
+                other.version = new_version.to_string();
+                bumped.push(other.name.clone());
+            }
+        }
+    }
+
+    let graph = ctx.graph();
+    for bumped in &bumped {
+        let Some(index) = graph.index(bumped) else {
+            continue;
+        };
+        let mut dependents: Vec<usize> = graph
+            .dependents(index, &DependencyKind::ALL)
+            .map(|(dependent, _)| dependent)
+            .collect();
+        dependents.dedup();
+        for dependent in dependents {
+            let dep = graph.name(dependent);
+            log::info!("Updating {bumped} to {new_version} for {dep}");
+            update_deps(&ctx.crates[dep], bumped, new_version)?;
         }
     }
 
-    let c = ctx.crates.get(name).unwrap();
+    let c = &ctx.crates[name];
     update_changelog(&ctx.root, c)?;
 
     Ok(())
 }
 
-fn update_crate(c: &mut Crate, new_version: &str) -> Result<()> {
+/// Write the crate's new version to its manifest, or to the workspace root if inherited.
+///
+/// Returns `true` if the workspace root was updated.
+fn update_crate(c: &Crate) -> Result<bool> {
+    if inherits_version(c)? {
+        let root = c
+            .workspace
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} inherits its version but has no workspace root", c.name))?;
+        let path = root.join("Cargo.toml");
+        let mut doc = read_manifest(&path)?;
+        let Some(Item::Table(package)) =
+            doc.get_mut("workspace").and_then(|w| w.get_mut("package"))
+        else {
+            return Err(anyhow!(
+                "{} inherits its version, but {} has no [workspace.package] table",
+                c.name,
+                path.display()
+            ));
+        };
+        package.insert("version", Item::Value(Value::from(c.version.as_str())));
+        fs::write(&path, doc.to_string())?;
+        return Ok(true);
+    }
+
     let path = c.path.join("Cargo.toml");
-    let content = fs::read_to_string(&path)?;
-    let mut doc: DocumentMut = content.parse()?;
+    let mut doc = read_manifest(&path)?;
     for section in ["package"] {
         if let Some(Item::Table(dep_table)) = doc.get_mut(section) {
-            dep_table.insert("version", Item::Value(Value::from(new_version)));
+            dep_table.insert("version", Item::Value(Value::from(c.version.as_str())));
         }
     }
     fs::write(&path, doc.to_string())?;
-    Ok(())
+    Ok(false)
 }
 
 fn update_deps(to_update: &Crate, dep: &CrateId, new_version: &str) -> Result<()> {
     let path = to_update.path.join("Cargo.toml");
-    let content = fs::read_to_string(&path)?;
-    let mut doc: DocumentMut = content.parse()?;
+    let mut doc = read_manifest(&path)?;
     let mut changed = false;
-    for section in ["dependencies", "dev-dependencies", "build-dependencies"] {
-        if let Some(Item::Table(dep_table)) = doc.get_mut(section)
-            && let Some(item) = dep_table.get_mut(dep)
+    let mut inherited = Vec::new();
+    for edge in to_update
+        .intra_repo_edges()
+        .filter(|edge| edge.name == *dep)
+    {
+        let table = match &edge.target {
+            // e.g., [target.'cfg(...)'.dependencies]
+            Some(target) => doc.get_mut("target").and_then(|t| t.get_mut(target)),
+            None => Some(doc.as_item_mut()),
+        };
+        // Renamed dependencies are keyed by their new name, e.g. `foo = { package = "..." }`
+        if let Some(item) = table
+            .and_then(|t| t.get_mut(edge.kind.section()))
+            .and_then(|t| t.get_mut(edge.key()))
         {
-            match item {
-                // e.g., foo = "0.1.0"
-                Item::Value(Value::String(_)) => {
-                    *item = Item::Value(Value::from(new_version));
-                    changed = true;
-                }
-                // e.g., foo = { version = "...", ... }
-                Item::Value(Value::InlineTable(inline)) => {
-                    if inline.contains_key("version") {
-                        inline["version"] = Value::from(new_version);
-                        changed = true;
-                    }
-                }
-                _ => {} // Leave unusual formats untouched
+            if is_inherited(item) {
+                // e.g., foo = { workspace = true }
+                inherited.push(edge.key());
+            } else {
+                changed |= update_version(item, new_version);
             }
         }
     }
 
     if changed {
         fs::write(&path, doc.to_string())?;
-        println!(
+        log::info!(
             "🔧 Updated {} to {} in {}",
             dep,
             new_version,
             path.display()
         );
     }
+
+    if !inherited.is_empty() {
+        let root = to_update.workspace.as_ref().ok_or_else(|| {
+            anyhow!(
+                "{} inherits {} but has no workspace root",
+                to_update.name,
+                dep
+            )
+        })?;
+        let path = root.join("Cargo.toml");
+        let mut doc = read_manifest(&path)?;
+        let mut changed = false;
+        if let Some(Item::Table(dep_table)) = doc
+            .get_mut("workspace")
+            .and_then(|w| w.get_mut("dependencies"))
+        {
+            for key in inherited {
+                if let Some(item) = dep_table.get_mut(key) {
+                    changed |= update_version(item, new_version);
+                }
+            }
+        }
+        if changed {
+            fs::write(&path, doc.to_string())?;
+            log::info!(
+                "🔧 Updated {} to {} in {}",
+                dep,
+                new_version,
+                path.display()
+            );
+        }
+    }
     Ok(())
 }
 
+/// Set the version of a dependency entry, returning whether it changed.
+fn update_version(item: &mut Item, new_version: &str) -> bool {
+    match item {
+        // e.g., foo = "0.1.0"
+        Item::Value(Value::String(old)) if old.value() != new_version => {
+            *item = Item::Value(Value::from(new_version));
+            true
+        }
+        // e.g., foo = { version = "...", ... }
+        Item::Value(Value::InlineTable(inline)) => match inline.get("version") {
+            Some(old) if old.as_str() != Some(new_version) => {
+                inline["version"] = Value::from(new_version);
+                true
+            }
+            _ => false,
+        },
+        _ => false, // Leave unusual formats untouched
+    }
+}
+
+/// Whether an item is `{ workspace = true }` or `foo.workspace = true`.
+fn is_inherited(item: &Item) -> bool {
+    item.get("workspace").and_then(|w| w.as_bool()) == Some(true)
+}
+
+fn inherits_version(c: &Crate) -> Result<bool> {
+    let doc = read_manifest(&c.path.join("Cargo.toml"))?;
+    Ok(doc
+        .get("package")
+        .and_then(|p| p.get("version"))
+        .is_some_and(is_inherited))
+}
+
+fn read_manifest(path: &Path) -> Result<DocumentMut> {
+    let content = fs::read_to_string(path)?;
+    Ok(content.parse()?)
+}
+
 fn update_changelog(repo: &Path, c: &Crate) -> Result<()> {
     let args: Vec<String> = vec![
         "release".to_string(),
@@ -91,13 +216,79 @@ fn update_changelog(repo: &Path, c: &Crate) -> Result<()> {
         "--no-confirm".to_string(),
     ];
 
-    let status = std::process::Command::new("cargo").args(&args).output()?;
+    let output = std::process::Command::new("cargo").args(&args).output()?;
 
-    println!("{}", core::str::from_utf8(&status.stdout).unwrap());
-    eprintln!("{}", core::str::from_utf8(&status.stderr).unwrap());
-    if !status.status.success() {
-        Err(anyhow!("release replace failed"))
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        log::info!("{}", stdout.trim_end());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        Err(anyhow!("release replace failed:\n{}", stderr.trim_end()))
     } else {
+        if !stderr.trim().is_empty() {
+            log::info!("{}", stderr.trim_end());
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TempDir};
+
+    fn krate(path: &Path, workspace: &Path) -> Crate {
+        let mut krate = testutil::krate("embassy-a", &[]);
+        krate.version = "0.2.0".to_string();
+        krate.path = path.to_path_buf();
+        krate.workspace = Some(workspace.to_path_buf());
+        krate
+    }
+
+    #[test]
+    fn inherited_version_is_written_to_workspace() {
+        let tmp = TempDir::new("bump-inherited");
+        let root = tmp.write(
+            "Cargo.toml",
+            "[workspace]\n\n[workspace.package]\nversion = \"0.1.0\"\n",
+        );
+        tmp.write(
+            "embassy-a/Cargo.toml",
+            "[package]\nname = \"embassy-a\"\nversion.workspace = true\n",
+        );
+
+        let c = krate(&tmp.0.join("embassy-a"), &tmp.0);
+        assert!(update_crate(&c).unwrap());
+        let doc = read_manifest(&root).unwrap();
+        assert_eq!(
+            doc["workspace"]["package"]["version"].as_str(),
+            Some("0.2.0")
+        );
+    }
+
+    #[test]
+    fn unknown_crate() {
+        let tmp = TempDir::new("bump-unknown");
+        let mut ctx = Context::new(tmp.0.clone(), Default::default(), Default::default());
+        let err = bump(&mut ctx, &"embassy-nope".to_string(), "0.1.0").unwrap_err();
+        assert_eq!(err.to_string(), "Crate 'embassy-nope' not found");
+    }
+
+    #[test]
+    fn missing_workspace_package_table() {
+        let tmp = TempDir::new("bump-no-package");
+        tmp.write("Cargo.toml", "[workspace]\nmembers = [\"embassy-a\"]\n");
+        tmp.write(
+            "embassy-a/Cargo.toml",
+            "[package]\nname = \"embassy-a\"\nversion.workspace = true\n",
+        );
+
+        let c = krate(&tmp.0.join("embassy-a"), &tmp.0);
+        let err = update_crate(&c).unwrap_err();
+        assert!(
+            err.to_string().contains("no [workspace.package] table"),
+            "{err}"
+        );
+    }
+}
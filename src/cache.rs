@@ -0,0 +1,288 @@
+//! On-disk cache of discovered crates, so manifests are only parsed again when they change.
+//!
+//! The cache lives in `target/embassy-devtool/cache.json` below the repository root. Entries are
+//! keyed by manifest path and hold the size and content hash of the manifest and of the manifests
+//! that decide its workspace: those in the directories above the crate, up to its workspace root.
+//! Contents are always compared, as modification times miss edits within the same second, and
+//! touching or re-checking out a file doesn't invalidate it.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG_FILE;
+use crate::types::Crate;
+
+/// What discovery made of a manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CachedManifest {
+    /// A crate, and the directory of the crate it shadows, if any.
+    Crate {
+        krate: Crate,
+        shadows: Option<PathBuf>,
+    },
+    /// A virtual workspace manifest or a skipped crate.
+    Ignored,
+}
+
+/// Version of the cache format, including the format of the cached [`Crate`]s. Bump it on any
+/// change so old caches are discarded instead of relying on them failing to deserialize.
+const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FileStamp {
+    len: u64,
+    hash: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    stamp: FileStamp,
+    /// Stamps of the manifests above a crate that decide its workspace, `None` where there was
+    /// no manifest. See [`workspace_manifests`].
+    workspace: Vec<(PathBuf, Option<FileStamp>)>,
+    manifest: CachedManifest,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    /// Schema version, tool version and config hash. The whole cache is discarded when this
+    /// changes.
+    key: String,
+    entries: HashMap<PathBuf, CacheEntry>,
+    /// Where to save the cache. Caches without a path are never saved.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Cache {
+    /// Path of the cache for the repository at `root`.
+    pub fn path(root: &Path) -> PathBuf {
+        root.join("target")
+            .join("embassy-devtool")
+            .join("cache.json")
+    }
+
+    /// Load the cache of the repository at `root`, starting from scratch if it's missing,
+    /// unreadable or was written by another version of the tool or for another config.
+    pub fn load(root: &Path) -> Self {
+        let path = Self::path(root);
+        let config = fs::read(root.join(CONFIG_FILE)).unwrap_or_default();
+        let key = format!(
+            "{}-{}-{:016x}",
+            SCHEMA_VERSION,
+            env!("CARGO_PKG_VERSION"),
+            hash(&config)
+        );
+
+        let entries = match fs::read(&path) {
+            Ok(content) => match serde_json::from_slice::<Cache>(&content) {
+                Ok(cache) if cache.key == key => cache.entries,
+                Ok(_) => {
+                    log::debug!("Discarding outdated cache {}", path.display());
+                    HashMap::new()
+                }
+                Err(e) => {
+                    log::debug!("Discarding invalid cache {}: {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        Self {
+            key,
+            entries,
+            path: Some(path),
+        }
+    }
+
+    /// The cached result for the manifest at `cargo_toml`, if neither it nor the manifests that
+    /// decide its workspace changed since it was cached.
+    pub fn get(&self, cargo_toml: &Path) -> Option<CachedManifest> {
+        let entry = self.entries.get(cargo_toml)?;
+        let unchanged = |path: &Path, cached: Option<&FileStamp>| {
+            let content = fs::read(path).ok();
+            cached == content.as_deref().map(stamp).as_ref()
+        };
+        if !unchanged(cargo_toml, Some(&entry.stamp))
+            || !entry
+                .workspace
+                .iter()
+                .all(|(path, cached)| unchanged(path, cached.as_ref()))
+        {
+            return None;
+        }
+        Some(entry.manifest.clone())
+    }
+
+    /// Cache the result for the manifest at `cargo_toml`, which was parsed from `content`.
+    pub fn insert(&mut self, cargo_toml: &Path, content: &str, manifest: CachedManifest) {
+        let workspace = match &manifest {
+            CachedManifest::Crate { krate, .. } => workspace_manifests(krate)
+                .into_iter()
+                .map(|path| {
+                    let stamp = fs::read(&path).ok().map(|content| stamp(&content));
+                    (path, stamp)
+                })
+                .collect(),
+            CachedManifest::Ignored => Vec::new(),
+        };
+        self.entries.insert(
+            cargo_toml.to_path_buf(),
+            CacheEntry {
+                stamp: stamp(content.as_bytes()),
+                workspace,
+                manifest,
+            },
+        );
+    }
+
+    /// Drop entries of manifests that no longer exist or are no longer discovered.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Path) -> bool) {
+        self.entries.retain(|path, _| keep(path));
+    }
+
+    /// Write the cache back to disk.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so concurrent runs never see a partial cache
+        let tmp = path.with_extension(format!("json.{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Manifests that decide the workspace of `krate` and what it inherits: those in the
+/// directories above it up to its workspace root, or all of them if it has none. Adding a
+/// workspace root above a crate, or removing one, changes one of them.
+fn workspace_manifests(krate: &Crate) -> Vec<PathBuf> {
+    let mut dirs: Vec<&Path> = Vec::new();
+    for dir in krate.path.ancestors() {
+        // The crate's own manifest is stamped anyway
+        if dir != krate.path {
+            dirs.push(dir);
+        }
+        if krate.workspace.as_deref() == Some(dir) {
+            break;
+        }
+    }
+    // Workspaces set with `package.workspace` need not be above the crate
+    if let Some(workspace) = &krate.workspace
+        && !dirs.contains(&workspace.as_path())
+    {
+        dirs.push(workspace);
+    }
+    dirs.into_iter().map(|dir| dir.join("Cargo.toml")).collect()
+}
+
+fn stamp(content: &[u8]) -> FileStamp {
+    FileStamp {
+        len: content.len() as u64,
+        hash: hash(content),
+    }
+}
+
+/// 64-bit FNV-1a, which is stable across Rust versions unlike `std`'s hasher.
+fn hash(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TempDir};
+
+    fn krate(path: &Path, workspace: Option<&Path>) -> CachedManifest {
+        let mut krate = testutil::krate("embassy-a", &[]);
+        krate.path = path.to_path_buf();
+        krate.workspace = workspace.map(Path::to_path_buf);
+        CachedManifest::Crate {
+            krate,
+            shadows: None,
+        }
+    }
+
+    fn write_crate(dir: &Path, content: &str) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        let cargo_toml = dir.join("Cargo.toml");
+        fs::write(&cargo_toml, content).unwrap();
+        cargo_toml
+    }
+
+    #[test]
+    fn edit_with_same_length_invalidates() {
+        let tmp = TempDir::new("cache-edit");
+        let dir = tmp.0.join("embassy-a");
+        let content = "[package]\nname = \"embassy-a\"\nversion = \"0.1.0\"\n";
+        let cargo_toml = write_crate(&dir, content);
+
+        let mut cache = Cache::default();
+        cache.insert(&cargo_toml, content, krate(&dir, None));
+        assert!(cache.get(&cargo_toml).is_some());
+
+        // Same length and most likely the same modification time
+        fs::write(&cargo_toml, content.replace("0.1.0", "0.2.0")).unwrap();
+        assert!(cache.get(&cargo_toml).is_none());
+    }
+
+    #[test]
+    fn workspace_root_added_above_invalidates() {
+        let tmp = TempDir::new("cache-workspace");
+        let dir = tmp.0.join("crates").join("embassy-a");
+        let content = "[package]\nname = \"embassy-a\"\nversion = \"0.1.0\"\n";
+        let cargo_toml = write_crate(&dir, content);
+
+        let mut cache = Cache::default();
+        cache.insert(&cargo_toml, content, krate(&dir, None));
+        assert!(cache.get(&cargo_toml).is_some());
+
+        write_crate(&tmp.0, "[workspace]\nmembers = [\"crates/*\"]\n");
+        assert!(cache.get(&cargo_toml).is_none());
+    }
+
+    #[test]
+    fn workspace_root_edit_invalidates() {
+        let tmp = TempDir::new("cache-inherit");
+        let workspace = "[workspace.package]\nversion = \"0.1.0\"\n";
+        write_crate(&tmp.0, workspace);
+        let dir = tmp.0.join("embassy-a");
+        let content = "[package]\nname = \"embassy-a\"\nversion.workspace = true\n";
+        let cargo_toml = write_crate(&dir, content);
+
+        let mut cache = Cache::default();
+        cache.insert(&cargo_toml, content, krate(&dir, Some(&tmp.0)));
+        assert!(cache.get(&cargo_toml).is_some());
+
+        write_crate(&tmp.0, &workspace.replace("0.1.0", "0.2.0"));
+        assert!(cache.get(&cargo_toml).is_none());
+    }
+
+    #[test]
+    fn outdated_key_is_discarded() {
+        let tmp = TempDir::new("cache-key");
+        let dir = tmp.0.join("embassy-a");
+        let content = "[package]\nname = \"embassy-a\"\nversion = \"0.1.0\"\n";
+        let cargo_toml = write_crate(&dir, content);
+
+        let mut cache = Cache::load(&tmp.0);
+        cache.insert(&cargo_toml, content, krate(&dir, None));
+        cache.save().unwrap();
+        assert!(Cache::load(&tmp.0).get(&cargo_toml).is_some());
+
+        cache.key = format!("{}-{}", SCHEMA_VERSION - 1, env!("CARGO_PKG_VERSION"));
+        cache.save().unwrap();
+        assert!(Cache::load(&tmp.0).get(&cargo_toml).is_none());
+    }
+}
@@ -1,14 +1,23 @@
 //! Tools for working with Cargo.
 
 use std::ffi::OsStr;
-use std::io::Write;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
 
 use anyhow::{Result, bail};
 
 use crate::windows_safe_path;
 
+/// A line printed by a cargo command, without the trailing newline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputLine {
+    Stdout(String),
+    Stderr(String),
+}
+
 /// Execute cargo with the given arguments and from the specified directory.
 pub fn run_with_env<I, K, V>(args: &[String], cwd: &Path, envs: I, capture: bool) -> Result<String>
 where
@@ -16,90 +25,28 @@ where
     K: AsRef<OsStr>,
     V: AsRef<OsStr>,
 {
-    if !cwd.is_dir() {
-        bail!("The `cwd` argument MUST be a directory");
-    }
-
-    // Make sure to not use a UNC as CWD!
-    // That would make `OUT_DIR` a UNC which will trigger things like the one fixed in https://github.com/dtolnay/rustversion/pull/51
-    // While it's fixed in `rustversion` it's not fixed for other crates we are
-    // using now or in future!
-    let cwd = windows_safe_path(cwd);
-
-    let args_str = args.join(" ");
-    let truncated_args = if args_str.len() > 100 {
-        format!("{}... ({} chars)", &args_str[..97], args_str.len())
-    } else {
-        args_str.clone()
-    };
-
     println!(
         "Running `cargo {}` in {:?} - Environment {:?}",
-        truncated_args, cwd, envs
+        display_args(args),
+        windows_safe_path(cwd),
+        envs
     );
 
-    let mut command = Command::new(get_cargo());
-
-    let output = if args[0] == "batch" {
-        let mut subargs = args.split(|x| *x == "---");
-
-        let stdin_arg = "--stdin".to_string();
-        let args: Vec<_> = subargs.next().unwrap().iter().chain([&stdin_arg]).collect();
-
-        command
-            .args(&args)
-            .current_dir(cwd)
-            .envs(envs)
-            .stdout(if capture {
-                Stdio::piped()
-            } else {
-                Stdio::inherit()
-            })
-            .stderr(if capture {
-                Stdio::piped()
-            } else {
-                Stdio::inherit()
-            })
-            .stdin(Stdio::piped());
-
-        if args.iter().any(|a| a.starts_with('+')) {
-            // Make sure the right cargo runs
-            command.env_remove("CARGO");
-        }
-
-        let mut child = command.spawn()?;
-        {
-            let mut stdin = child.stdin.take().unwrap();
-
-            for args in subargs {
-                writeln!(stdin, "{}", shell_words::join(args)).unwrap();
-            }
-
-            // drop stdin to close the pipe
+    let (mut command, stdin_lines) = command(args, cwd, envs)?;
+    let stdio = || {
+        if capture {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
         }
+    };
+    command.stdout(stdio()).stderr(stdio());
 
+    let output = if let Some(stdin_lines) = stdin_lines {
+        let mut child = command.stdin(Stdio::piped()).spawn()?;
+        write_stdin(&mut child, &stdin_lines);
         child.wait_with_output()?
     } else {
-        command
-            .args(args)
-            .current_dir(cwd)
-            .envs(envs)
-            .stdout(if capture {
-                Stdio::piped()
-            } else {
-                Stdio::inherit()
-            })
-            .stderr(if capture {
-                Stdio::piped()
-            } else {
-                Stdio::inherit()
-            });
-
-        if args.iter().any(|a| a.starts_with('+')) {
-            // Make sure the right cargo runs
-            command.env_remove("CARGO");
-        }
-
         command.stdin(Stdio::inherit()).output()?
     };
 
@@ -108,18 +55,160 @@ where
     if output.status.success() {
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     } else {
-        let truncated_args = if args_str.len() > 100 {
-            format!("{}... ({} chars)", &args_str[..97], args_str.len())
+        bail!(
+            "Failed to execute cargo subcommand `cargo {}`",
+            display_args(args),
+        )
+    }
+}
+
+/// Execute cargo like [`run_with_env`], passing each line of its output to `on_line` instead of
+/// printing it.
+///
+/// Colors are kept if stdout is a terminal, as the output is assumed to end up there.
+pub fn run_with_env_streaming<I, K, V>(
+    args: &[String],
+    cwd: &Path,
+    envs: I,
+    mut on_line: impl FnMut(OutputLine),
+) -> Result<()>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    let (mut command, stdin_lines) = command(args, cwd, envs)?;
+    if std::io::stdout().is_terminal() && std::env::var_os("CARGO_TERM_COLOR").is_none() {
+        command.env("CARGO_TERM_COLOR", "always");
+    }
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdin(if stdin_lines.is_some() {
+            Stdio::piped()
         } else {
-            args_str
-        };
+            Stdio::null()
+        });
+
+    let mut child = command.spawn()?;
+    if let Some(stdin_lines) = stdin_lines {
+        write_stdin(&mut child, &stdin_lines);
+    }
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|s| {
+        let stdout_tx = tx.clone();
+        s.spawn(move || read_lines(stdout, |line| stdout_tx.send(OutputLine::Stdout(line))));
+        s.spawn(move || read_lines(stderr, |line| tx.send(OutputLine::Stderr(line))));
+        for line in rx {
+            on_line(line);
+        }
+    });
+
+    if child.wait()?.success() {
+        Ok(())
+    } else {
         bail!(
             "Failed to execute cargo subcommand `cargo {}`",
-            truncated_args,
+            display_args(args),
         )
     }
 }
 
+/// Arguments as shown in logs and errors, truncated if they're long.
+pub fn display_args(args: &[String]) -> String {
+    let args_str = args.join(" ");
+    if args_str.len() > 100 {
+        format!("{}... ({} chars)", &args_str[..97], args_str.len())
+    } else {
+        args_str
+    }
+}
+
+/// Remove the color codes from cargo's output, e.g. from diagnostics rendered with
+/// `--message-format=json-diagnostic-rendered-ansi`.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end with a byte in `@`..=`~`
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Set up the cargo command. For `cargo batch`, the steps to write to its stdin are returned too.
+fn command<I, K, V>(args: &[String], cwd: &Path, envs: I) -> Result<(Command, Option<Vec<String>>)>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    if !cwd.is_dir() {
+        bail!("The `cwd` argument MUST be a directory");
+    }
+
+    // Make sure to not use a UNC as CWD!
+    // That would make `OUT_DIR` a UNC which will trigger things like the one fixed in https://github.com/dtolnay/rustversion/pull/51
+    // While it's fixed in `rustversion` it's not fixed for other crates we are
+    // using now or in future!
+    let cwd = windows_safe_path(cwd);
+
+    let mut command = Command::new(get_cargo());
+    command.current_dir(cwd).envs(envs);
+
+    let stdin_lines = if args[0] == "batch" {
+        let mut subargs = args.split(|x| *x == "---");
+        let batch_args = subargs.next().unwrap();
+        command.args(batch_args).arg("--stdin");
+        Some(subargs.map(shell_words::join).collect())
+    } else {
+        command.args(args);
+        None
+    };
+
+    if args.iter().any(|a| a.starts_with('+')) {
+        // Make sure the right cargo runs
+        command.env_remove("CARGO");
+    }
+
+    Ok((command, stdin_lines))
+}
+
+fn write_stdin(child: &mut Child, lines: &[String]) {
+    let mut stdin = child.stdin.take().unwrap();
+    for line in lines {
+        writeln!(stdin, "{}", line).unwrap();
+    }
+    // drop stdin to close the pipe
+}
+
+fn read_lines<T>(reader: impl Read, mut send: impl FnMut(String) -> Result<(), T>) {
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    while let Ok(n) = reader.read_until(b'\n', &mut buf)
+        && n > 0
+    {
+        let line = String::from_utf8_lossy(&buf);
+        if send(line.trim_end_matches(['\n', '\r']).to_string()).is_err() {
+            break;
+        }
+        buf.clear();
+    }
+}
+
 fn get_cargo() -> String {
     // On Windows when executed via `cargo run` (e.g. via the xtask alias) the
     // `cargo` on the search path is NOT the cargo-wrapper but the `cargo` from the
@@ -136,3 +225,17 @@ fn get_cargo() -> String {
 
     cargo
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_colors() {
+        assert_eq!(
+            strip_ansi("\x1b[0m\x1b[1m\x1b[33mwarning\x1b[0m: unused variable"),
+            "warning: unused variable"
+        );
+        assert_eq!(strip_ansi("no colors"), "no colors");
+    }
+}
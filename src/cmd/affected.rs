@@ -0,0 +1,46 @@
+use anyhow::Result;
+use cargo_embassy_devtool::affected::affected_since;
+use cargo_embassy_devtool::types::Context;
+use serde::Serialize;
+
+use crate::cmd::{OutputFormat, print_json};
+
+/// List the crates changed since a git ref, and the crates depending on them
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Git ref to compare against, e.g. `origin/main`. Changes are counted from where the
+    /// current branch diverged from it, including uncommitted ones.
+    #[arg(long, value_name = "REF")]
+    pub since: String,
+}
+
+#[derive(Serialize)]
+struct AffectedJson<'a> {
+    since: &'a str,
+    /// Crates containing changed files.
+    changed: Vec<&'a str>,
+    /// Changed crates and their recursive dependents.
+    affected: Vec<&'a str>,
+}
+
+pub fn run(ctx: &Context, args: Args, format: OutputFormat) -> Result<()> {
+    let affected = affected_since(ctx, &args.since)?;
+
+    if format == OutputFormat::Json {
+        return print_json(&AffectedJson {
+            since: &args.since,
+            changed: affected.changed.iter().map(|name| name.as_str()).collect(),
+            affected: affected.affected.iter().map(|name| name.as_str()).collect(),
+        });
+    }
+
+    for name in &affected.affected {
+        let krate = &ctx.crates[name];
+        if affected.changed.contains(name) {
+            println!("+ {}-{} (changed)", name, krate.version);
+        } else {
+            println!("+ {}-{}", name, krate.version);
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,21 @@
-use crate::types::Context;
-use anyhow::{Result, anyhow};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::IsTerminal;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use anyhow::{Result, anyhow, bail};
+use cargo_embassy_devtool::Context;
+use cargo_embassy_devtool::affected::affected_since;
+use cargo_embassy_devtool::build::{
+    BatchEvent, BatchResult, BatchStatus, BuildBatch, BuildCommand, RunOptions, plan, run_batches,
+};
+use cargo_embassy_devtool::cargo::{OutputLine, display_args, strip_ansi};
+use cargo_embassy_devtool::diagnostics::{CargoMessage, Diagnostics, parse_message};
+use cargo_embassy_devtool::matrix::{History, Shard, shard};
+use cargo_embassy_devtool::report::{Report, ReportOutput};
+use serde::Serialize;
+
+use crate::cmd::{OutputFormat, print_json};
 
 /// Build
 #[derive(Debug, clap::Args)]
@@ -14,25 +29,65 @@ pub struct Args {
     /// Also build all dependents of the specified crate
     #[arg(long)]
     pub dependents: bool,
+    /// Build the crates changed since this git ref and their dependents, instead of a single crate.
+    #[arg(long, value_name = "REF", conflicts_with_all = ["crate_name", "dependents"])]
+    pub changed_since: Option<String>,
+    /// Number of batches to run in parallel, each with its own target directory.
+    #[arg(long, short = 'j', default_value = "1")]
+    pub jobs: NonZeroUsize,
+    /// Keep building after a batch failed, and find out which configs of failed batches fail.
+    #[arg(long)]
+    pub keep_going: bool,
+    /// Write a report with the result of every config, as `junit=<path>` or `json=<path>`. Can be given more than once.
+    #[arg(long, value_name = "FORMAT=PATH")]
+    pub report: Vec<ReportOutput>,
+    /// Fail if the compiler reported any warnings.
+    #[arg(long)]
+    pub deny_warnings: bool,
+    /// Only build this shard of the configs, as split by the `matrix` command, e.g. `2/4`.
+    ///
+    /// Shards cover all crates, like `matrix` does, so a crate can't be selected as well.
+    #[arg(long, value_name = "I/N", conflicts_with_all = ["crate_name", "dependents", "changed_since"])]
+    pub shard: Option<Shard>,
+    /// JSON report of a previous build that the shards were balanced with, see `matrix --history`.
+    #[arg(long, value_name = "PATH", requires = "shard")]
+    pub history: Option<PathBuf>,
+    /// Print the batches and cargo commands that would run, without running them.
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct BuildConfigBatch {
-    pub env: std::collections::BTreeMap<String, String>,
-    pub build_std: Vec<String>,
+#[derive(Serialize)]
+struct BatchJson<'a> {
+    env: BTreeMap<String, String>,
+    build_std: &'a [String],
+    /// Arguments to cargo for the whole batch, with steps separated by `---`.
+    args: Vec<String>,
+    steps: Vec<StepJson<'a>>,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum BuildCommand {
-    Build,
-    Check,
+#[derive(Serialize)]
+struct StepJson<'a> {
+    #[serde(rename = "crate")]
+    krate: &'a str,
+    group: Option<&'a str>,
+    target: Option<&'a str>,
+    features: &'a [String],
+    artifact_dir: Option<&'a str>,
+    /// Arguments to cargo for this step.
+    args: &'a [String],
 }
 
-pub fn run(ctx: &Context, args: Args) -> Result<()> {
-    run_build_command(ctx, args, BuildCommand::Build)
+pub fn run(ctx: &Context, args: Args, format: OutputFormat) -> Result<()> {
+    run_build_command(ctx, args, BuildCommand::Build, format)
 }
 
-pub fn run_build_command(ctx: &Context, args: Args, build_command: BuildCommand) -> Result<()> {
+pub fn run_build_command(
+    ctx: &Context,
+    args: Args,
+    build_command: BuildCommand,
+    format: OutputFormat,
+) -> Result<()> {
     let crate_name = args.crate_name.as_deref();
     let group = args.group.as_deref();
 
@@ -43,7 +98,14 @@ pub fn run_build_command(ctx: &Context, args: Args, build_command: BuildCommand)
         ));
     }
 
-    let crates_to_build: Vec<_> = if let Some(name) = crate_name {
+    let crates_to_build: Vec<_> = if let Some(since) = &args.changed_since {
+        let affected = affected_since(ctx, since)?;
+        affected
+            .affected
+            .iter()
+            .map(|name| &ctx.crates[name])
+            .collect()
+    } else if let Some(name) = crate_name {
         if !ctx.crates.contains_key(name) {
             return Err(anyhow!("Crate '{}' not found", name));
         }
@@ -67,76 +129,353 @@ pub fn run_build_command(ctx: &Context, args: Args, build_command: BuildCommand)
         ctx.crates.values().collect()
     };
 
-    let mut batch_groups: HashMap<BuildConfigBatch, Vec<(String, &crate::types::BuildConfig)>> =
-        HashMap::new();
+    let mut batches = plan(crates_to_build, group, build_command);
+    if let Some(selected) = args.shard {
+        let history = match &args.history {
+            Some(path) => History::load(path)?,
+            None => History::default(),
+        };
+        batches = shard(&batches, selected.count, &history)
+            .swap_remove(selected.index)
+            .batches;
+    }
+    let options = RunOptions {
+        jobs: args.jobs.get(),
+        keep_going: args.keep_going,
+    };
+    if args.dry_run {
+        return print_plan(ctx, &batches, &options, format);
+    }
+
+    // Output of parallel batches is interleaved, so tell it apart by prefixing it
+    let parallel = options.jobs > 1 && batches.len() > 1;
+    let label = |i: usize| format!("[{}/{}]", i + 1, batches.len());
 
-    for krate in crates_to_build {
-        for config in &krate.configs {
-            if config.group.as_deref() != group {
-                continue;
-            }
+    let print = |i: usize, line: &str, stderr: bool| {
+        let line = if parallel {
+            format!("{} {}", label(i), line)
+        } else {
+            line.to_string()
+        };
+        if stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    };
 
-            let batch_key = BuildConfigBatch {
-                env: config.env.clone(),
-                build_std: config.build_std.clone(),
-            };
+    // Diagnostics are always rendered with colors so the planned commands don't depend on the
+    // terminal, strip them here instead
+    let color = std::io::stderr().is_terminal();
+    let mut diagnostics = Diagnostics::default();
+    // The step of each batch that is being built on its own after the batch failed. Until then
+    // diagnostics can only be told apart by package
+    let mut single_step: Vec<Option<usize>> = vec![None; batches.len()];
+    let results = run_batches(&batches, &ctx.root, &options, |event| match event {
+        BatchEvent::Started(i) => println!(
+            "🔧 {} Running `cargo {}` - Environment {:?}",
+            label(i),
+            display_args(&batches[i].args()),
+            options.env(&batches[i], i, &ctx.root)
+        ),
+        BatchEvent::Output(i, OutputLine::Stdout(line)) => match parse_message(line, &ctx.root) {
+            Some(CargoMessage::Diagnostic(diagnostic)) => {
+                let batch = &batches[i];
+                let configs: Vec<&str> = match single_step[i] {
+                    Some(j) => vec![batch.steps[j].id.as_str()],
+                    None => batch
+                        .steps_of(&diagnostic.package)
+                        .into_iter()
+                        .map(|step| step.id.as_str())
+                        .collect(),
+                };
+                let rendered = diagnostic.rendered.clone();
+                // Configs of the same crate mostly report the same diagnostics, only show them once
+                if diagnostics.add(diagnostic, &configs)
+                    && let Some(mut rendered) = rendered
+                {
+                    if !color {
+                        rendered = strip_ansi(&rendered);
+                    }
+                    for line in rendered.lines() {
+                        print(i, line, true);
+                    }
+                }
+            }
+            Some(CargoMessage::BuildFinished { .. } | CargoMessage::Other) => {}
+            None => print(i, line, false),
+        },
+        BatchEvent::Output(i, OutputLine::Stderr(line)) => print(i, line, true),
+        BatchEvent::StepStarted(i, j) => {
+            single_step[i] = Some(j);
+            println!(
+                "🔧 {} Building {} on its own to find the failure",
+                label(i),
+                batches[i].steps[j].id
+            )
+        }
+        BatchEvent::StepFinished(i, j, result) => match &result.status {
+            BatchStatus::Passed => println!("✅ {} {} passed", label(i), batches[i].steps[j].id),
+            _ => eprintln!("❌ {} {} failed", label(i), batches[i].steps[j].id),
+        },
+        BatchEvent::Finished(i, result) => match &result.status {
+            BatchStatus::Passed => println!(
+                "✅ {} Finished in {:.1}s",
+                label(i),
+                result.duration.as_secs_f64()
+            ),
+            BatchStatus::Failed(e) => eprintln!("❌ {} {}", label(i), e),
+            BatchStatus::Skipped => {}
+        },
+    });
 
-            let crate_path = format!("{}/Cargo.toml", krate.path.to_string_lossy());
-            batch_groups
-                .entry(batch_key)
-                .or_default()
-                .push((crate_path, config));
+    if !args.report.is_empty() {
+        let name = match build_command {
+            BuildCommand::Build => "build",
+            BuildCommand::Check => "check",
+        };
+        let report = Report::new(name, &batches, &results, &diagnostics);
+        for output in &args.report {
+            report.write(output)?;
         }
     }
 
-    for (batch_config, configs) in batch_groups {
-        let mut batch_args = vec!["batch".to_string()];
-        if !batch_config.build_std.is_empty() {
-            batch_args.push(format!("-Zbuild-std={}", batch_config.build_std.join(",")));
+    let count =
+        |status: fn(&BatchStatus) -> bool| results.iter().filter(|r| status(&r.status)).count();
+    let failed = count(|s| matches!(s, BatchStatus::Failed(_)));
+    if batches.len() > 1 {
+        println!();
+        println!(
+            "Summary: {} passed, {} failed, {} skipped",
+            count(|s| *s == BatchStatus::Passed),
+            failed,
+            count(|s| *s == BatchStatus::Skipped)
+        );
+        if !options.keep_going {
+            for (i, result) in results.iter().enumerate() {
+                if matches!(result.status, BatchStatus::Failed(_)) {
+                    println!("❌ {} {}", label(i), describe(&batches[i]));
+                }
+            }
         }
+    }
+    if options.keep_going && failed > 0 {
+        println!();
+        print_failures(&batches, &results, label);
+    }
+    if diagnostics.errors().next().is_some() {
+        println!();
+        print_errors(&diagnostics);
+    }
+    let warnings = diagnostics.warnings().count();
+    if warnings > 0 {
+        println!();
+        print_warnings(&diagnostics);
+    }
 
-        for (manifest_path, config) in configs {
-            let mut args = match build_command {
-                BuildCommand::Build => vec![
-                    "build".to_string(),
-                    "--release".to_string(),
-                    format!("--manifest-path={}", manifest_path),
-                ],
-                BuildCommand::Check => vec![
-                    "check".to_string(),
-                    format!("--manifest-path={}", manifest_path),
-                ],
-            };
+    if failed > 0 {
+        bail!("{} of {} batches failed", failed, batches.len());
+    }
+    if args.deny_warnings && warnings > 0 {
+        bail!("{} warnings, denied by --deny-warnings", warnings);
+    }
+    Ok(())
+}
 
-            if let Some(ref target) = config.target {
-                args.push(format!("--target={}", target));
-            }
-            if !config.features.is_empty() {
-                args.push(format!("--features={}", config.features.join(",")));
-            }
-            if matches!(build_command, BuildCommand::Build)
-                && let Some(ref artifact_dir) = config.artifact_dir
-            {
-                args.push(format!("--artifact-dir={}", artifact_dir));
-            }
+fn print_plan(
+    ctx: &Context,
+    batches: &[BuildBatch],
+    options: &RunOptions,
+    format: OutputFormat,
+) -> Result<()> {
+    if format == OutputFormat::Json {
+        let batches: Vec<_> = batches
+            .iter()
+            .enumerate()
+            .map(|(i, batch)| BatchJson {
+                env: options.env(batch, i, &ctx.root),
+                build_std: &batch.config.build_std,
+                args: batch.args(),
+                steps: batch
+                    .steps
+                    .iter()
+                    .map(|step| StepJson {
+                        krate: &step.krate.name,
+                        group: step.config.group.as_deref(),
+                        target: step.config.target.as_deref(),
+                        features: &step.config.features,
+                        artifact_dir: step.config.artifact_dir.as_deref(),
+                        args: &step.args,
+                    })
+                    .collect(),
+            })
+            .collect();
+        return print_json(&batches);
+    }
 
-            batch_args.push("---".to_string());
-            batch_args.extend(args);
+    for (i, batch) in batches.iter().enumerate() {
+        println!("Batch {}/{}", i + 1, batches.len());
+        for (key, value) in options.env(batch, i, &ctx.root) {
+            println!("  {}={}", key, value);
+        }
+        if !batch.config.build_std.is_empty() {
+            println!("  -Zbuild-std={}", batch.config.build_std.join(","));
         }
+        for step in &batch.steps {
+            println!("  cargo {}", shell_words::join(&step.args));
+        }
+        println!();
+    }
+    println!(
+        "{} batches, {} steps",
+        batches.len(),
+        batches.iter().map(|batch| batch.steps.len()).sum::<usize>()
+    );
+    Ok(())
+}
 
-        let mut final_env = batch_config.env.clone();
-        if let Some(config_rustflags) = final_env.get("RUSTFLAGS")
-            && let Ok(existing_rustflags) = std::env::var("RUSTFLAGS")
-            && !existing_rustflags.is_empty()
-        {
-            final_env.insert(
-                "RUSTFLAGS".to_string(),
-                format!("{} {}", existing_rustflags, config_rustflags),
+/// Unique errors with every config they were reported for, as the console only showed each once.
+fn print_errors(diagnostics: &Diagnostics) {
+    println!("Errors:");
+    for entry in diagnostics.errors() {
+        let diagnostic = &entry.diagnostic;
+        let code = match &diagnostic.code {
+            Some(code) => format!("[{}]", code),
+            None => String::new(),
+        };
+        println!("{}{}: {}", diagnostic.level, code, diagnostic.message);
+        if let Some(file) = &diagnostic.file {
+            println!(
+                "  --> {}:{}:{}",
+                file.display(),
+                diagnostic.line,
+                diagnostic.column
             );
         }
+        for config in &entry.configs {
+            println!("  in {}", config);
+        }
+    }
+}
 
-        crate::cargo::run_with_env(&batch_args, &ctx.root, &final_env, false)?;
+/// Number of unique warnings per lint and per file.
+fn print_warnings(diagnostics: &Diagnostics) {
+    let mut lints: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut files: BTreeMap<String, usize> = BTreeMap::new();
+    let mut configs = BTreeSet::new();
+    for entry in diagnostics.warnings() {
+        let diagnostic = &entry.diagnostic;
+        *lints
+            .entry(diagnostic.code.as_deref().unwrap_or("(no lint)"))
+            .or_default() += 1;
+        let file = match &diagnostic.file {
+            Some(file) => file.display().to_string(),
+            None => "(no file)".to_string(),
+        };
+        *files.entry(file).or_default() += 1;
+        configs.extend(&entry.configs);
     }
 
-    Ok(())
+    println!(
+        "Warnings: {} unique, reported by {} configs",
+        lints.values().sum::<usize>(),
+        configs.len()
+    );
+    for (heading, counts) in [
+        (
+            "By lint:",
+            lints.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+        ),
+        ("By file:", files.into_iter().collect::<Vec<_>>()),
+    ] {
+        let mut counts = counts;
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        println!("{}", heading);
+        for (name, count) in counts {
+            println!("{:>6}  {}", count, name);
+        }
+    }
+}
+
+/// Table of the configs that failed, from the steps of failed batches built one by one.
+fn print_failures(
+    batches: &[BuildBatch],
+    results: &[BatchResult],
+    label: impl Fn(usize) -> String,
+) {
+    let mut rows = vec![[
+        "BATCH".to_string(),
+        "CRATE".to_string(),
+        "TARGET".to_string(),
+        "FEATURES".to_string(),
+    ]];
+    for (i, result) in results.iter().enumerate() {
+        if !matches!(result.status, BatchStatus::Failed(_)) {
+            continue;
+        }
+        let failed_steps: Vec<_> = result
+            .steps
+            .iter()
+            .zip(&batches[i].steps)
+            .filter(|(result, _)| matches!(result.status, BatchStatus::Failed(_)))
+            .map(|(_, step)| step)
+            .collect();
+        if failed_steps.is_empty() {
+            // Every step passed on its own, so only the batch as a whole can be blamed
+            rows.push([
+                label(i),
+                describe(&batches[i]),
+                "-".to_string(),
+                "-".to_string(),
+            ]);
+        }
+        for step in failed_steps {
+            rows.push([
+                label(i),
+                step.krate.name.clone(),
+                step.config
+                    .target
+                    .clone()
+                    .unwrap_or_else(|| "host".to_string()),
+                step.config.features.join(","),
+            ]);
+        }
+    }
+
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    println!("Failures:");
+    for row in &rows {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Short description of what a batch builds, for the summary.
+fn describe(batch: &BuildBatch) -> String {
+    let mut crates: Vec<_> = batch
+        .steps
+        .iter()
+        .map(|step| step.krate.name.as_str())
+        .collect();
+    crates.dedup();
+    let mut out = crates.join(", ");
+    if !batch.config.env.is_empty() {
+        out.push_str(&format!(" - Environment {:?}", batch.config.env));
+    }
+    if !batch.config.build_std.is_empty() {
+        out.push_str(&format!(
+            " - build-std {}",
+            batch.config.build_std.join(",")
+        ));
+    }
+    out
 }
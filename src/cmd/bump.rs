@@ -1,6 +1,6 @@
-use crate::bump::bump;
-use crate::types::Context;
 use anyhow::Result;
+use cargo_embassy_devtool::bump::bump;
+use cargo_embassy_devtool::types::Context;
 
 #[derive(Debug, clap::Args)]
 /// Force set a dependency to a version.
@@ -1,9 +1,9 @@
-use crate::{
-    cmd::build::{Args, BuildCommand, run_build_command},
-    types::Context,
-};
+use crate::cmd::OutputFormat;
+use crate::cmd::build::{Args, run_build_command};
 use anyhow::Result;
+use cargo_embassy_devtool::Context;
+use cargo_embassy_devtool::build::BuildCommand;
 
-pub fn run(ctx: &Context, args: Args) -> Result<()> {
-    run_build_command(ctx, args, BuildCommand::Check)
+pub fn run(ctx: &Context, args: Args, format: OutputFormat) -> Result<()> {
+    run_build_command(ctx, args, BuildCommand::Check, format)
 }
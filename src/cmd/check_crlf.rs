@@ -1,8 +1,8 @@
-use crate::types::Context;
 use anyhow::{Result, anyhow};
+use cargo_embassy_devtool::types::Context;
+use cargo_embassy_devtool::walk::files;
 use std::fs;
 use std::path::Path;
-use walkdir::WalkDir;
 
 #[derive(Debug, clap::Args)]
 /// Check that all files in the repository have LF line endings (no CRLF)
@@ -12,13 +12,10 @@ pub fn run(ctx: &Context, _args: Args) -> Result<()> {
     let mut files_with_crlf = Vec::new();
 
     // Walk through all files in the repository
-    for entry in WalkDir::new(&ctx.root)
-        .into_iter()
-        .filter_entry(|e| !is_ignored_path(e.path()))
-    {
-        let entry = entry?;
-        let path = entry.path();
-
+    let files = files(&ctx.root, &ctx.config.discovery, |path, _| {
+        !is_ignored_path(path)
+    })?;
+    for path in &files {
         // Only check regular files
         if !path.is_file() {
             continue;
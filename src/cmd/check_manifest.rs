@@ -1,7 +1,7 @@
-use crate::types::Context;
 use anyhow::{Result, anyhow};
-use std::collections::HashSet;
-use toml_edit::{DocumentMut, Item};
+use cargo_embassy_devtool::types::{Context, Crate};
+use std::collections::BTreeSet;
+use toml_edit::{DocumentMut, Item, Table};
 
 #[derive(Debug, clap::Args)]
 /// Check that all Cargo.toml files have correct metadata and feature configuration
@@ -19,19 +19,39 @@ pub fn run(ctx: &Context, _args: Args) -> Result<()> {
             .parse()
             .map_err(|e| anyhow!("Failed to parse {}: {}", cargo_toml_path.display(), e))?;
 
+        let workspace_doc = match &krate.workspace {
+            Some(root) => {
+                let path = root.join("Cargo.toml");
+                let content = std::fs::read_to_string(&path)
+                    .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+                Some(
+                    content
+                        .parse::<DocumentMut>()
+                        .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))?,
+                )
+            }
+            None => None,
+        };
+        let workspace_package = workspace_doc
+            .as_ref()
+            .and_then(|doc| doc.get("workspace"))
+            .and_then(|ws| ws.get("package"));
+
         // Check package metadata
-        if let Err(e) = check_package_metadata(&doc, crate_name, krate.publish) {
+        if let Err(e) = check_package_metadata(&doc, workspace_package, crate_name, krate.publish) {
             errors.push(format!("{}: {}", crate_name, e));
         }
 
         // Check features - only for publishable crates
         if krate.publish
-            && let Err(e) = check_features(&doc)
+            && let Err(e) = check_features(krate)
         {
             errors.push(format!("{}: {}", crate_name, e));
         }
     }
 
+    errors.extend(check_path_dependency_versions(ctx));
+
     if errors.is_empty() {
         println!("✅ All manifests are correct!");
         Ok(())
@@ -43,12 +63,41 @@ pub fn run(ctx: &Context, _args: Args) -> Result<()> {
     }
 }
 
-fn check_package_metadata(doc: &DocumentMut, crate_name: &str, is_publishable: bool) -> Result<()> {
+/// Path dependencies whose version requirement no longer accepts the version of the crate in
+/// the repository, which breaks publishing the depending crate.
+fn check_path_dependency_versions(ctx: &Context) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (krate, edge) in ctx.edges() {
+        let dep = &ctx.crates[&edge.name];
+        if edge.path.is_some()
+            && let Some(req) = &edge.version_req
+            && let Ok(version) = semver::Version::parse(&dep.version)
+            && !req.matches(&version)
+        {
+            errors.push(format!(
+                "{}: requirement '{}' on {} does not match its version {}",
+                krate.name, req, edge.name, dep.version
+            ));
+        }
+    }
+    errors
+}
+
+fn check_package_metadata(
+    doc: &DocumentMut,
+    workspace_package: Option<&Item>,
+    crate_name: &str,
+    is_publishable: bool,
+) -> Result<()> {
     let package = doc
         .get("package")
         .ok_or_else(|| anyhow!("missing [package] section"))?
         .as_table()
         .ok_or_else(|| anyhow!("[package] is not a table"))?;
+    let package = InheritedPackage {
+        package,
+        workspace_package,
+    };
 
     // Check edition
     let edition = package
@@ -111,62 +160,48 @@ fn check_package_metadata(doc: &DocumentMut, crate_name: &str, is_publishable: b
     Ok(())
 }
 
-fn check_features(doc: &DocumentMut) -> Result<()> {
-    // Get all optional dependencies
-    let mut optional_deps: HashSet<String> = HashSet::new();
-
-    // Check dependencies
-    if let Some(deps) = doc.get("dependencies").and_then(|d| d.as_table()) {
-        for (name, value) in deps.iter() {
-            if is_optional_dependency(value) {
-                optional_deps.insert(name.to_string());
-            }
-        }
-    }
+/// Looks up `[package]` fields, following `field.workspace = true` to `[workspace.package]`.
+struct InheritedPackage<'a> {
+    package: &'a Table,
+    workspace_package: Option<&'a Item>,
+}
 
-    // Check dev-dependencies
-    if let Some(deps) = doc.get("dev-dependencies").and_then(|d| d.as_table()) {
-        for (name, value) in deps.iter() {
-            if is_optional_dependency(value) {
-                optional_deps.insert(name.to_string());
-            }
+impl<'a> InheritedPackage<'a> {
+    fn get(&self, field: &str) -> Option<&'a Item> {
+        let item = self.package.get(field)?;
+        if item.get("workspace").and_then(|w| w.as_bool()) == Some(true) {
+            self.workspace_package?.get(field)
+        } else {
+            Some(item)
         }
     }
+}
 
-    // Check build-dependencies
-    if let Some(deps) = doc.get("build-dependencies").and_then(|d| d.as_table()) {
-        for (name, value) in deps.iter() {
-            if is_optional_dependency(value) {
-                optional_deps.insert(name.to_string());
-            }
-        }
-    }
+fn check_features(krate: &Crate) -> Result<()> {
+    // Get all optional dependencies
+    let optional_deps: BTreeSet<&str> = krate
+        .dependencies
+        .iter()
+        .filter(|dep| dep.optional)
+        .map(|dep| dep.key())
+        .collect();
 
     if optional_deps.is_empty() {
         return Ok(()); // No optional dependencies to check
     }
 
     // Get all features that reference dependencies
-    let mut referenced_deps: HashSet<String> = HashSet::new();
-
-    if let Some(features) = doc.get("features").and_then(|f| f.as_table()) {
-        for (_feature_name, feature_value) in features.iter() {
-            if let Some(feature_list) = feature_value.as_array() {
-                for item in feature_list.iter() {
-                    if let Some(item_str) = item.as_str()
-                        && let Some(dep_name) = item_str.strip_prefix("dep:")
-                    {
-                        referenced_deps.insert(dep_name.to_string());
-                    }
-                }
-            }
-        }
-    }
+    let referenced_deps: BTreeSet<&str> = krate
+        .features
+        .values()
+        .flatten()
+        .filter_map(|item| item.strip_prefix("dep:"))
+        .collect();
 
     // Find unreferenced optional dependencies
-    let unreferenced: Vec<String> = optional_deps
+    let unreferenced: Vec<&str> = optional_deps
         .difference(&referenced_deps)
-        .cloned()
+        .copied()
         .collect();
 
     if !unreferenced.is_empty() {
@@ -179,12 +214,36 @@ fn check_features(doc: &DocumentMut) -> Result<()> {
     Ok(())
 }
 
-fn is_optional_dependency(value: &Item) -> bool {
-    match value {
-        Item::Value(toml_edit::Value::InlineTable(table)) => table
-            .get("optional")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false),
-        _ => false,
+#[cfg(test)]
+mod tests {
+    use cargo_embassy_devtool::testutil;
+    use cargo_embassy_devtool::types::DependencyKind::{Dev, Normal};
+
+    use super::*;
+
+    #[test]
+    fn path_dependency_version_mismatch() {
+        let mut ctx = testutil::context(
+            &["embassy-a", "embassy-b", "embassy-c"],
+            &[
+                ("embassy-a", "embassy-b", Normal),
+                ("embassy-a", "embassy-c", Dev),
+                ("embassy-b", "embassy-c", Normal),
+            ],
+        );
+        ctx.crates.get_mut("embassy-c").unwrap().version = "0.2.0".to_string();
+        for krate in ctx.crates.values_mut() {
+            for edge in &mut krate.dependencies {
+                edge.path = Some(edge.name.clone().into());
+                edge.version_req = Some("0.1".parse().unwrap());
+            }
+        }
+        // Only path dependencies are checked
+        ctx.crates.get_mut("embassy-a").unwrap().dependencies[1].path = None;
+
+        assert_eq!(
+            check_path_dependency_versions(&ctx),
+            ["embassy-b: requirement '^0.1' on embassy-c does not match its version 0.2.0"]
+        );
     }
 }
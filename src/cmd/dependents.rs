@@ -1,28 +1,26 @@
-use crate::types::Context;
 use anyhow::Result;
+use cargo_embassy_devtool::types::Context;
 
-/// List all dependencies for a crate
+use crate::cmd::OutputFormat;
+use crate::cmd::tree::{self, Direction, TreeArgs};
+
+/// Show the tree of crates depending on a crate
 #[derive(Debug, clap::Args)]
 pub struct Args {
-    /// Crate name to print dependencies for.
+    /// Crate name to print dependents for.
     #[arg(value_name = "CRATE")]
     pub crate_name: String,
-}
 
-pub fn run(ctx: &Context, args: Args) -> Result<()> {
-    if let Some(krate) = ctx.crates.get(&args.crate_name) {
-        println!("+ {}-{}", args.crate_name, krate.version);
+    #[command(flatten)]
+    pub tree: TreeArgs,
+}
 
-        let dependents = ctx.recursive_dependents(std::iter::once(args.crate_name.as_str()));
-        for dependent_name in dependents {
-            if dependent_name != args.crate_name
-                && let Some(dependent_crate) = ctx.crates.get(&dependent_name)
-            {
-                println!("|- {}-{}", dependent_name, dependent_crate.version);
-            }
-        }
-    } else {
-        eprintln!("Crate '{}' not found", args.crate_name);
-    }
-    Ok(())
+pub fn run(ctx: &Context, args: Args, format: OutputFormat) -> Result<()> {
+    tree::run(
+        ctx,
+        &args.crate_name,
+        &args.tree,
+        Direction::Dependents,
+        format,
+    )
 }
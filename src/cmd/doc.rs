@@ -1,5 +1,6 @@
-use crate::types::Context;
 use anyhow::Result;
+use cargo_embassy_devtool::affected::affected_since;
+use cargo_embassy_devtool::types::Context;
 use std::path::PathBuf;
 use std::process::Command;
 
@@ -10,6 +11,10 @@ pub struct Args {
     #[clap(long = "crate")]
     pub crates: Vec<String>,
 
+    /// Build docs for the publishable crates changed since this git ref and their dependents
+    #[clap(long, value_name = "REF", conflicts_with = "crates")]
+    pub changed_since: Option<String>,
+
     /// Output directory for generated documentation
     #[clap(short, long)]
     pub output: PathBuf,
@@ -20,7 +25,14 @@ pub struct Args {
 }
 
 pub fn run(ctx: &Context, args: Args) -> Result<()> {
-    let crates_to_build = if args.crates.is_empty() {
+    let crates_to_build = if let Some(since) = &args.changed_since {
+        // Build docs for affected publishable crates
+        affected_since(ctx, since)?
+            .affected
+            .into_iter()
+            .filter(|crate_id| ctx.crates[crate_id].publish && ctx.crates[crate_id].doc)
+            .collect()
+    } else if args.crates.is_empty() {
         // Build docs for all publishable crates
         ctx.crates
             .iter()
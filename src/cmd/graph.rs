@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use anyhow::{Result, bail};
+use cargo_embassy_devtool::types::{Context, DependencyKind};
+
+use crate::cmd::{EdgeKind, OutputFormat, dependency_kinds, print_json};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// Mermaid flowchart
+    Mermaid,
+}
+
+/// Export the crate graph as Graphviz DOT, Mermaid or JSON
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Only include this crate and its recursive dependencies.
+    #[arg(value_name = "CRATE")]
+    pub crate_name: Option<String>,
+
+    /// Dependency kinds to include.
+    #[arg(long, value_delimiter = ',', default_values = ["normal", "build", "dev"])]
+    pub edges: Vec<EdgeKind>,
+
+    /// Only include crates that are published.
+    #[arg(long)]
+    pub publishable_only: bool,
+
+    /// Graph language of the text output. `--format json` prints the nodes and edges as JSON instead.
+    #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+    pub graph_format: GraphFormat,
+}
+
+/// Crates in the graph and the edges between them.
+struct Graph<'a> {
+    nodes: BTreeSet<&'a str>,
+    edges: BTreeSet<(&'a str, &'a str, DependencyKind)>,
+}
+
+pub fn run(ctx: &Context, args: Args, format: OutputFormat) -> Result<()> {
+    let kinds = dependency_kinds(&args.edges);
+    let graph = build_graph(
+        ctx,
+        args.crate_name.as_deref(),
+        &kinds,
+        args.publishable_only,
+    )?;
+    match format {
+        OutputFormat::Json => print_json(&to_json(ctx, &graph)),
+        OutputFormat::Text => {
+            match args.graph_format {
+                GraphFormat::Dot => print!("{}", to_dot(ctx, &graph)),
+                GraphFormat::Mermaid => print!("{}", to_mermaid(ctx, &graph)),
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The crates reachable from `root` through edges of `kinds`, or all crates without a root.
+fn build_graph<'a>(
+    ctx: &'a Context,
+    root: Option<&str>,
+    kinds: &'a [DependencyKind],
+    publishable_only: bool,
+) -> Result<Graph<'a>> {
+    let included = |name: &str| {
+        ctx.crates
+            .get(name)
+            .is_some_and(|krate| !publishable_only || krate.publish)
+    };
+
+    let nodes: BTreeSet<&str> = match root {
+        Some(root) => {
+            let Some((root, _)) = ctx.crates.get_key_value(root) else {
+                bail!("Crate '{}' not found", root);
+            };
+            let mut nodes = BTreeSet::from([root.as_str()]);
+            let mut stack = vec![root.as_str()];
+            while let Some(crate_name) = stack.pop() {
+                for edge in ctx.edges_of_kinds(crate_name, kinds) {
+                    if included(&edge.name) && nodes.insert(edge.name.as_str()) {
+                        stack.push(edge.name.as_str());
+                    }
+                }
+            }
+            nodes
+        }
+        None => ctx
+            .crates
+            .keys()
+            .map(|name| name.as_str())
+            .filter(|name| included(name))
+            .collect(),
+    };
+
+    let edges = nodes
+        .iter()
+        .flat_map(|&from| {
+            ctx.edges_of_kinds(from, kinds)
+                .filter(|edge| nodes.contains(edge.name.as_str()))
+                .map(move |edge| (from, edge.name.as_str(), edge.kind))
+        })
+        .collect();
+    Ok(Graph { nodes, edges })
+}
+
+fn kind_label(kind: DependencyKind) -> &'static str {
+    match kind {
+        DependencyKind::Normal => "normal",
+        DependencyKind::Build => "build",
+        DependencyKind::Dev => "dev",
+    }
+}
+
+fn to_dot(ctx: &Context, graph: &Graph) -> String {
+    let mut out = String::from("digraph crates {\n    rankdir=LR;\n    node [shape=box];\n");
+    for name in &graph.nodes {
+        let krate = &ctx.crates[*name];
+        let style = if krate.publish { "" } else { ", style=dashed" };
+        let name = dot_escape(name);
+        writeln!(
+            out,
+            "    \"{}\" [label=\"{}\\n{}\"{}];",
+            name,
+            name,
+            dot_escape(&krate.version),
+            style
+        )
+        .unwrap();
+    }
+    for (from, to, kind) in &graph.edges {
+        let attrs = match kind {
+            DependencyKind::Normal => String::new(),
+            kind => format!(" [style=dashed, label=\"{}\"]", kind_label(*kind)),
+        };
+        writeln!(
+            out,
+            "    \"{}\" -> \"{}\"{};",
+            dot_escape(from),
+            dot_escape(to),
+            attrs
+        )
+        .unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn to_mermaid(ctx: &Context, graph: &Graph) -> String {
+    // Crate names aren't valid Mermaid ids in general, so number the nodes
+    let ids: BTreeMap<&str, usize> = graph
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (*name, i))
+        .collect();
+
+    let mut out = String::from("flowchart LR\n");
+    for (name, id) in &ids {
+        let label = format!("{} {}", name, ctx.crates[*name].version);
+        writeln!(out, "    n{}[\"{}\"]", id, mermaid_escape(&label)).unwrap();
+    }
+    for (from, to, kind) in &graph.edges {
+        let arrow = match kind {
+            DependencyKind::Normal => "-->".to_string(),
+            kind => format!("-. {} .->", kind_label(*kind)),
+        };
+        writeln!(out, "    n{} {} n{}", ids[from], arrow, ids[to]).unwrap();
+    }
+    out
+}
+
+/// Escape `s` for a double-quoted DOT string.
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escape `s` for a double-quoted Mermaid label, which has entity codes instead of backslashes.
+fn mermaid_escape(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
+
+fn to_json(ctx: &Context, graph: &Graph) -> serde_json::Value {
+    let nodes: Vec<_> = graph
+        .nodes
+        .iter()
+        .map(|name| {
+            let krate = &ctx.crates[*name];
+            serde_json::json!({
+                "name": name,
+                "version": krate.version,
+                "publish": krate.publish,
+            })
+        })
+        .collect();
+    let edges: Vec<_> = graph
+        .edges
+        .iter()
+        .map(|(from, to, kind)| {
+            serde_json::json!({
+                "from": from,
+                "to": to,
+                "kind": kind,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "nodes": nodes,
+        "edges": edges,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use DependencyKind::{Build, Dev, Normal};
+    use cargo_embassy_devtool::testutil;
+
+    use super::*;
+
+    /// `embassy-a` depends on `embassy-b` under another name and on `em"bassy\c` for tests,
+    /// which `embassy-b` needs to build. `em"bassy\c` isn't published.
+    fn context() -> Context {
+        let c = "em\"bassy\\c";
+        let mut ctx = testutil::context(
+            &["embassy-a", "embassy-b", c],
+            &[
+                ("embassy-a", "embassy-b", Normal),
+                ("embassy-a", c, Dev),
+                ("embassy-b", c, Build),
+            ],
+        );
+        let a = ctx.crates.get_mut("embassy-a").unwrap();
+        a.dependencies[0].rename = Some("b".to_string());
+        ctx.crates.get_mut(c).unwrap().publish = false;
+        ctx
+    }
+
+    fn graph(ctx: &Context) -> Graph<'_> {
+        build_graph(ctx, None, &DependencyKind::ALL, false).unwrap()
+    }
+
+    #[test]
+    fn dot() {
+        let ctx = context();
+        assert_eq!(
+            to_dot(&ctx, &graph(&ctx)),
+            r#"digraph crates {
+    rankdir=LR;
+    node [shape=box];
+    "em\"bassy\\c" [label="em\"bassy\\c\n0.1.0", style=dashed];
+    "embassy-a" [label="embassy-a\n0.1.0"];
+    "embassy-b" [label="embassy-b\n0.1.0"];
+    "embassy-a" -> "em\"bassy\\c" [style=dashed, label="dev"];
+    "embassy-a" -> "embassy-b";
+    "embassy-b" -> "em\"bassy\\c" [style=dashed, label="build"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn mermaid() {
+        let ctx = context();
+        assert_eq!(
+            to_mermaid(&ctx, &graph(&ctx)),
+            r#"flowchart LR
+    n0["em#quot;bassy\c 0.1.0"]
+    n1["embassy-a 0.1.0"]
+    n2["embassy-b 0.1.0"]
+    n1 -. dev .-> n0
+    n1 --> n2
+    n2 -. build .-> n0
+"#
+        );
+    }
+
+    #[test]
+    fn json() {
+        let ctx = context();
+        assert_eq!(
+            to_json(&ctx, &graph(&ctx)),
+            serde_json::json!({
+                "nodes": [
+                    {"name": "em\"bassy\\c", "version": "0.1.0", "publish": false},
+                    {"name": "embassy-a", "version": "0.1.0", "publish": true},
+                    {"name": "embassy-b", "version": "0.1.0", "publish": true},
+                ],
+                "edges": [
+                    {"from": "embassy-a", "to": "em\"bassy\\c", "kind": "dev"},
+                    {"from": "embassy-a", "to": "embassy-b", "kind": "normal"},
+                    {"from": "embassy-b", "to": "em\"bassy\\c", "kind": "build"},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn reachable_from_root() {
+        let ctx = context();
+        let graph = build_graph(&ctx, Some("embassy-a"), &[Normal], false).unwrap();
+        assert_eq!(Vec::from_iter(graph.nodes), ["embassy-a", "embassy-b"]);
+        assert_eq!(
+            Vec::from_iter(graph.edges),
+            [("embassy-a", "embassy-b", Normal)]
+        );
+
+        let graph = build_graph(&ctx, Some("embassy-a"), &DependencyKind::ALL, true).unwrap();
+        assert_eq!(Vec::from_iter(graph.nodes), ["embassy-a", "embassy-b"]);
+        assert!(build_graph(&ctx, Some("embassy-z"), &[Normal], false).is_err());
+    }
+}
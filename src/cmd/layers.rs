@@ -0,0 +1,44 @@
+use anyhow::Result;
+use cargo_embassy_devtool::types::Context;
+use serde::Serialize;
+
+use crate::cmd::{EdgeKind, OutputFormat, dependency_kinds, print_json};
+
+/// Group crates into layers that can be built or published in parallel
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Dependency kinds that order crates.
+    #[arg(long, value_delimiter = ',', default_values = ["normal", "build"])]
+    pub edges: Vec<EdgeKind>,
+}
+
+#[derive(Serialize)]
+struct LayersJson<'a> {
+    layers: &'a [Vec<String>],
+    critical_path: &'a [String],
+}
+
+pub fn run(ctx: &Context, args: Args, format: OutputFormat) -> Result<()> {
+    let layers = ctx.layers(&dependency_kinds(&args.edges))?;
+
+    if format == OutputFormat::Json {
+        return print_json(&LayersJson {
+            layers: &layers.layers,
+            critical_path: &layers.critical_path,
+        });
+    }
+
+    for (i, layer) in layers.layers.iter().enumerate() {
+        println!("Layer {} ({} crates):", i, layer.len());
+        for name in layer {
+            println!("+ {}-{}", name, ctx.crates[name].version);
+        }
+        println!();
+    }
+    println!(
+        "Critical path ({} layers): {}",
+        layers.critical_path.len(),
+        layers.critical_path.join(" <- ")
+    );
+    Ok(())
+}
@@ -1,12 +1,23 @@
-use crate::types::Context;
 use anyhow::Result;
+use cargo_embassy_devtool::types::Context;
+
+use crate::cmd::{CrateJson, OutputFormat, print_json};
 
 #[derive(Debug, clap::Args)]
 /// All crates and their direct dependencies
 pub struct Args;
 
-pub fn run(ctx: &Context, _args: Args) -> Result<()> {
+pub fn run(ctx: &Context, _args: Args, format: OutputFormat) -> Result<()> {
     let ordered = ctx.topological_sort();
+    if format == OutputFormat::Json {
+        let crates: Vec<_> = ordered
+            .iter()
+            .filter_map(|name| ctx.crates.get(name))
+            .map(|krate| CrateJson::new(ctx, krate))
+            .collect();
+        return print_json(&crates);
+    }
+
     for crate_name in ordered {
         if let Some(krate) = ctx.crates.get(&crate_name) {
             println!("+ {}-{}", crate_name, krate.version);
@@ -0,0 +1,110 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use cargo_embassy_devtool::build::{BuildCommand, plan};
+use cargo_embassy_devtool::matrix::{History, Shard, shard};
+use cargo_embassy_devtool::types::Context;
+use serde::Serialize;
+
+use crate::cmd::{OutputFormat, print_json};
+
+/// Split the build configs into shards for CI jobs, as a GitHub Actions matrix with `--format json`
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Number of shards to split the configs into.
+    #[arg(long, default_value = "1")]
+    pub shards: NonZeroUsize,
+    /// Group name. If specified it'll split all configs matching it, if not specified it'll split all configs with no group set.
+    #[arg(long)]
+    pub group: Option<String>,
+    /// JSON report of a previous build, to balance the shards by build time instead of config count.
+    #[arg(long, value_name = "PATH")]
+    pub history: Option<PathBuf>,
+}
+
+/// GitHub Actions matrix, used with `strategy.matrix: ${{ fromJSON(...) }}`.
+#[derive(Serialize)]
+struct MatrixJson<'a> {
+    include: Vec<ShardJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct ShardJson<'a> {
+    /// Value for `build --shard`.
+    shard: String,
+    group: Option<&'a str>,
+    batches: usize,
+    steps: Vec<&'a str>,
+    cost: f64,
+}
+
+pub fn run(ctx: &Context, args: Args, format: OutputFormat) -> Result<()> {
+    let history = match &args.history {
+        Some(path) => History::load(path)?,
+        None => History::default(),
+    };
+    let group = args.group.as_deref();
+    let batches = plan(ctx.crates.values(), group, BuildCommand::Build);
+    let shards = shard(&batches, args.shards.get(), &history);
+    let empty = shards
+        .iter()
+        .filter(|shard| shard.batches.is_empty())
+        .count();
+    if empty > 0 {
+        log::warn!(
+            "{} of {} shards are empty, as the configs are built by only {} batches",
+            empty,
+            shards.len(),
+            batches.len()
+        );
+    }
+    let name = |index: usize| {
+        Shard {
+            index,
+            count: shards.len(),
+        }
+        .to_string()
+    };
+
+    if format == OutputFormat::Json {
+        return print_json(&MatrixJson {
+            include: shards
+                .iter()
+                .enumerate()
+                .map(|(i, shard)| ShardJson {
+                    shard: name(i),
+                    group,
+                    batches: shard.batches.len(),
+                    steps: shard
+                        .batches
+                        .iter()
+                        .flat_map(|batch| &batch.steps)
+                        .map(|step| step.id.as_str())
+                        .collect(),
+                    cost: shard.cost,
+                })
+                .collect(),
+        });
+    }
+
+    for (i, shard) in shards.iter().enumerate() {
+        let steps: Vec<_> = shard
+            .batches
+            .iter()
+            .flat_map(|batch| &batch.steps)
+            .collect();
+        println!(
+            "Shard {} ({} batches, {} steps, cost {:.1}):",
+            name(i),
+            shard.batches.len(),
+            steps.len(),
+            shard.cost
+        );
+        for step in steps {
+            println!("+ {}", step.id);
+        }
+        println!();
+    }
+    Ok(())
+}
@@ -1,3 +1,10 @@
+use std::path::Path;
+
+use anyhow::Result;
+use cargo_embassy_devtool::types::{BuildConfig, Context, Crate, DependencyKind};
+use serde::Serialize;
+
+pub mod affected;
 pub mod build;
 pub mod bump;
 pub mod check;
@@ -6,6 +13,93 @@ pub mod check_manifest;
 pub mod dependencies;
 pub mod dependents;
 pub mod doc;
+pub mod graph;
+pub mod layers;
 pub mod list;
+pub mod matrix;
 pub mod prepare_release;
 pub mod semver_check;
+pub mod tree;
+pub mod why;
+
+/// Output format of the query commands, selected with the global `--format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// JSON on stdout, without the version banner
+    Json,
+}
+
+/// Dependency kind selectable with `--edges`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EdgeKind {
+    Normal,
+    Build,
+    Dev,
+}
+
+impl From<EdgeKind> for DependencyKind {
+    fn from(kind: EdgeKind) -> Self {
+        match kind {
+            EdgeKind::Normal => DependencyKind::Normal,
+            EdgeKind::Build => DependencyKind::Build,
+            EdgeKind::Dev => DependencyKind::Dev,
+        }
+    }
+}
+
+/// Convert the kinds given with `--edges`.
+pub fn dependency_kinds(kinds: &[EdgeKind]) -> Vec<DependencyKind> {
+    kinds.iter().map(|&kind| kind.into()).collect()
+}
+
+/// A crate as printed by `--format json`.
+#[derive(Debug, Serialize)]
+pub struct CrateJson<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    pub path: &'a Path,
+    pub publish: bool,
+    pub doc: bool,
+    pub configs: &'a [BuildConfig],
+    /// Direct intra-repo dependencies.
+    pub dependencies: Vec<DependencyJson<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DependencyJson<'a> {
+    pub name: &'a str,
+    pub kind: DependencyKind,
+    pub target: Option<&'a str>,
+    pub optional: bool,
+}
+
+impl<'a> CrateJson<'a> {
+    pub fn new(ctx: &Context, krate: &'a Crate) -> Self {
+        Self {
+            name: &krate.name,
+            version: &krate.version,
+            path: &krate.path,
+            publish: krate.publish,
+            doc: krate.doc,
+            configs: &krate.configs,
+            dependencies: krate
+                .intra_repo_edges()
+                .filter(|edge| ctx.crates.contains_key(&edge.name))
+                .map(|edge| DependencyJson {
+                    name: &edge.name,
+                    kind: edge.kind,
+                    target: edge.target.as_deref(),
+                    optional: edge.optional,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Print `value` as pretty JSON on stdout.
+pub fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
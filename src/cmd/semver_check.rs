@@ -1,27 +1,52 @@
-use crate::types::Context;
 use anyhow::{Result, anyhow, bail};
+use cargo_embassy_devtool::affected::affected_since;
+use cargo_embassy_devtool::types::Context;
 use cargo_semver_checks::{Check, GlobalConfig, ReleaseType, Rustdoc};
 use flate2::read::GzDecoder;
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use tar::Archive;
 
-use crate::types::{BuildConfig, Crate};
+use cargo_embassy_devtool::config::Discovery;
+use cargo_embassy_devtool::types::{BuildConfig, Crate, DependencyKind};
 
 /// SemverCheck
 #[derive(Debug, clap::Args)]
 pub struct Args {
     /// Specific crate name to check
-    #[arg(value_name = "CRATE")]
-    pub crate_name: String,
+    #[arg(value_name = "CRATE", required_unless_present = "changed_since")]
+    pub crate_name: Option<String>,
+
+    /// Check the publishable crates changed since this git ref and their dependents
+    #[arg(long, value_name = "REF", conflicts_with = "crate_name")]
+    pub changed_since: Option<String>,
 }
 
 pub fn run(ctx: &Context, args: Args) -> Result<()> {
-    let c = ctx.crates.get(&args.crate_name).unwrap();
+    if let Some(since) = &args.changed_since {
+        let crates: Vec<_> = affected_since(ctx, since)?
+            .affected
+            .into_iter()
+            .filter(|name| ctx.crates[name].publish)
+            .collect();
+        if crates.is_empty() {
+            println!("No publishable crates changed since {}", since);
+        }
+        for name in crates {
+            let min_version = minimum_update(ctx.root.clone(), &ctx.crates[&name])?;
+            println!("{}: version should be bumped to {min_version:?}", name);
+        }
+        return Ok(());
+    }
+
+    let crate_name = args.crate_name.unwrap();
+    let Some(c) = ctx.crates.get(&crate_name) else {
+        bail!("Crate '{}' not found", crate_name);
+    };
     if !c.publish {
         bail!(
             "Cannot run semver-check on non-publishable crate '{}'",
-            args.crate_name
+            crate_name
         );
     }
 
@@ -40,7 +65,9 @@ pub fn minimum_update(root: PathBuf, krate: &Crate) -> Result<ReleaseType, anyho
     baseline_krate.path = baseline_path.clone();
 
     // Compare features as it's not covered by semver-checks
-    if compare_features(&baseline_krate, krate)? {
+    let baseline_manifest =
+        cargo_embassy_devtool::discover::parse_crate(&baseline_path, &Discovery::default())?;
+    if compare_features(&baseline_manifest, krate) {
         return Ok(ReleaseType::Minor);
     }
 
@@ -89,13 +116,13 @@ pub fn minimum_update(root: PathBuf, krate: &Crate) -> Result<ReleaseType, anyho
     Ok(min_required_update)
 }
 
-fn compare_features(old: &Crate, new: &Crate) -> Result<bool, anyhow::Error> {
-    let mut old = read_features(&old.path)?;
-    let new = read_features(&new.path)?;
+fn compare_features(old: &Crate, new: &Crate) -> bool {
+    let mut old = read_features(old);
+    let new = read_features(new);
 
     old.retain(|r| !new.contains(r));
     log::info!("Features removed in new: {old:?}");
-    Ok(!old.is_empty())
+    !old.is_empty()
 }
 
 fn download_baseline(root: &Path, name: &str, version: &str) -> Result<PathBuf, anyhow::Error> {
@@ -128,30 +155,14 @@ fn download_baseline(root: &Path, name: &str, version: &str) -> Result<PathBuf,
     Ok(extract_path)
 }
 
-fn read_features(crate_path: &Path) -> Result<HashSet<String>, anyhow::Error> {
-    let cargo_toml_path = crate_path.join("Cargo.toml");
-
-    if !cargo_toml_path.exists() {
-        return Err(anyhow!("Cargo.toml not found at {:?}", cargo_toml_path));
-    }
-
-    let manifest = cargo_manifest::Manifest::from_path(&cargo_toml_path)?;
-
-    let mut set = HashSet::new();
-    if let Some(features) = manifest.features {
-        for f in features.keys() {
-            set.insert(f.clone());
+fn read_features(krate: &Crate) -> HashSet<String> {
+    let mut set: HashSet<String> = krate.features.keys().cloned().collect();
+    for dep in &krate.dependencies {
+        if dep.kind == DependencyKind::Normal && dep.optional {
+            set.insert(dep.key().to_string());
         }
     }
-    if let Some(deps) = manifest.dependencies {
-        for (k, v) in deps.iter() {
-            if v.optional() {
-                set.insert(k.clone());
-            }
-        }
-    }
-
-    Ok(set)
+    set
 }
 
 fn build_doc_json(krate: &Crate, config: &BuildConfig) -> Result<PathBuf, anyhow::Error> {
@@ -208,14 +219,11 @@ fn build_doc_json(krate: &Crate, config: &BuildConfig) -> Result<PathBuf, anyhow
     cargo_args
         .push("--config=host.rustflags=[\"--cfg=instability_disable_unstable_docs\"]".to_string());
     log::debug!("{cargo_args:#?}");
-    crate::cargo::run_with_env(&cargo_args, &krate.path, envs, false)?;
+    cargo_embassy_devtool::cargo::run_with_env(&cargo_args, &krate.path, envs, false)?;
     Ok(current_path)
 }
 
-pub fn check_semver(
-    root: std::path::PathBuf,
-    c: &crate::types::Crate,
-) -> Result<ReleaseType, anyhow::Error> {
+pub fn check_semver(root: std::path::PathBuf, c: &Crate) -> Result<ReleaseType, anyhow::Error> {
     let min_version = minimum_update(root, c)?;
     println!("Version should be bumped to {min_version:?}");
     Ok(min_version)
@@ -0,0 +1,268 @@
+//! `cargo tree`-style rendering shared by `dependencies` and `dependents`.
+
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt::Write;
+
+use anyhow::Result;
+use cargo_embassy_devtool::types::{Context, CrateId, DependencyKind};
+use serde::Serialize;
+
+use crate::cmd::{CrateJson, EdgeKind, OutputFormat, dependency_kinds, print_json};
+
+#[derive(Debug, clap::Args)]
+pub struct TreeArgs {
+    /// Maximum depth of the tree. 1 shows only direct dependencies.
+    #[arg(long)]
+    pub depth: Option<usize>,
+
+    /// Dependency kinds to follow.
+    #[arg(long, value_delimiter = ',', default_values = ["normal", "build", "dev"])]
+    pub edges: Vec<EdgeKind>,
+
+    /// Show the tree in the opposite direction.
+    #[arg(long, short = 'i')]
+    pub invert: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Dependencies,
+    Dependents,
+}
+
+#[derive(Serialize)]
+struct TreeJson<'a> {
+    #[serde(rename = "crate")]
+    krate: CrateJson<'a>,
+    /// Recursive dependencies, sorted by name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dependencies: Option<Vec<CrateJson<'a>>>,
+    /// Recursive dependents, sorted by name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dependents: Option<Vec<CrateJson<'a>>>,
+}
+
+pub fn run(
+    ctx: &Context,
+    crate_name: &str,
+    args: &TreeArgs,
+    direction: Direction,
+    format: OutputFormat,
+) -> Result<()> {
+    let Some((root, krate)) = ctx.crates.get_key_value(crate_name) else {
+        eprintln!("Crate '{}' not found", crate_name);
+        return Ok(());
+    };
+    let direction = match (direction, args.invert) {
+        (direction, false) => direction,
+        (Direction::Dependencies, true) => Direction::Dependents,
+        (Direction::Dependents, true) => Direction::Dependencies,
+    };
+    let mut tree = Tree {
+        ctx,
+        kinds: dependency_kinds(&args.edges),
+        direction,
+        max_depth: args.depth,
+        visited: HashSet::new(),
+        levels: Vec::new(),
+        out: String::new(),
+    };
+    if format == OutputFormat::Json {
+        let crates: Vec<_> = tree
+            .reachable(root)
+            .iter()
+            .map(|&name| CrateJson::new(ctx, &ctx.crates[name]))
+            .collect();
+        let (dependencies, dependents) = match direction {
+            Direction::Dependencies => (Some(crates), None),
+            Direction::Dependents => (None, Some(crates)),
+        };
+        return print_json(&TreeJson {
+            krate: CrateJson::new(ctx, krate),
+            dependencies,
+            dependents,
+        });
+    }
+
+    tree.render(root);
+    print!("{}", tree.out);
+    Ok(())
+}
+
+struct Tree<'a> {
+    ctx: &'a Context,
+    kinds: Vec<DependencyKind>,
+    direction: Direction,
+    max_depth: Option<usize>,
+    /// Crates whose subtree was already printed.
+    visited: HashSet<&'a str>,
+    /// For each level above the current crate, whether more siblings follow.
+    levels: Vec<bool>,
+    /// The rendered tree.
+    out: String,
+}
+
+impl<'a> Tree<'a> {
+    /// Neighbours of `crate_name` through edges of `kind`, sorted by name.
+    fn children(&self, crate_name: &str, kind: DependencyKind) -> BTreeSet<&'a CrateId> {
+        let graph = self.ctx.graph();
+        let index = graph.index(crate_name).unwrap();
+        let kinds = [kind];
+        let neighbors: Vec<_> = match self.direction {
+            Direction::Dependencies => graph.dependencies(index, &kinds).collect(),
+            Direction::Dependents => graph.dependents(index, &kinds).collect(),
+        };
+        neighbors.into_iter().map(|(i, _)| graph.name(i)).collect()
+    }
+
+    /// All crates within `max_depth` of `root`, excluding `root` itself.
+    fn reachable(&self, root: &'a str) -> BTreeSet<&'a CrateId> {
+        let mut seen = HashSet::from([root]);
+        let mut found = BTreeSet::new();
+        let mut queue = VecDeque::from([(root, 0)]);
+        while let Some((crate_name, depth)) = queue.pop_front() {
+            if self.max_depth.is_some_and(|max| depth >= max) {
+                continue;
+            }
+            for &kind in &self.kinds {
+                for child in self.children(crate_name, kind) {
+                    if seen.insert(child) {
+                        found.insert(child);
+                        queue.push_back((child, depth + 1));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    fn render(&mut self, crate_name: &'a str) {
+        let groups: Vec<_> = self
+            .kinds
+            .iter()
+            .map(|&kind| (kind, self.children(crate_name, kind)))
+            .filter(|(_, children)| !children.is_empty())
+            .collect();
+
+        let krate = &self.ctx.crates[crate_name];
+        let at_limit = self.max_depth.is_some_and(|max| self.levels.len() >= max);
+        // A crate first reached at the depth limit isn't expanded, so it doesn't count as printed
+        let repeated = !groups.is_empty() && !at_limit && self.visited.contains(crate_name);
+        writeln!(
+            self.out,
+            "{}{} v{}{}",
+            self.prefix(),
+            crate_name,
+            krate.version,
+            if repeated { " (*)" } else { "" }
+        )
+        .unwrap();
+        if repeated || at_limit {
+            return;
+        }
+        self.visited.insert(crate_name);
+
+        for (kind, children) in groups {
+            if kind != DependencyKind::Normal {
+                writeln!(self.out, "{}[{}]", self.continuation(), self.heading(kind)).unwrap();
+            }
+            let count = children.len();
+            for (i, child) in children.into_iter().enumerate() {
+                self.levels.push(i + 1 < count);
+                self.render(child);
+                self.levels.pop();
+            }
+        }
+    }
+
+    fn heading(&self, kind: DependencyKind) -> String {
+        match self.direction {
+            Direction::Dependencies => kind.section().to_string(),
+            Direction::Dependents => kind.section().replace("dependencies", "dependents"),
+        }
+    }
+
+    /// Tree lines leading to the current crate.
+    fn prefix(&self) -> String {
+        let mut out = String::new();
+        for (i, &more) in self.levels.iter().enumerate() {
+            out.push_str(match (i + 1 == self.levels.len(), more) {
+                (true, true) => "├── ",
+                (true, false) => "└── ",
+                (false, true) => "│   ",
+                (false, false) => "    ",
+            });
+        }
+        out
+    }
+
+    /// Tree lines continuing below the current crate.
+    fn continuation(&self) -> String {
+        self.levels
+            .iter()
+            .map(|&more| if more { "│   " } else { "    " })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cargo_embassy_devtool::testutil;
+
+    use super::*;
+
+    /// A context with crates depending on each other through normal `edges` of (from, to).
+    fn context(names: &[&str], edges: &[(&str, &str)]) -> Context {
+        let edges: Vec<_> = edges
+            .iter()
+            .map(|&(from, to)| (from, to, DependencyKind::Normal))
+            .collect();
+        testutil::context(names, &edges)
+    }
+
+    fn render(ctx: &Context, root: &str, max_depth: Option<usize>) -> String {
+        let mut tree = Tree {
+            ctx,
+            kinds: vec![DependencyKind::Normal],
+            direction: Direction::Dependencies,
+            max_depth,
+            visited: HashSet::new(),
+            levels: Vec::new(),
+            out: String::new(),
+        };
+        tree.render(root);
+        tree.out
+    }
+
+    #[test]
+    fn repeated_crates_are_marked() {
+        let ctx = context(
+            &["a", "b", "c", "d"],
+            &[("a", "b"), ("a", "c"), ("b", "c"), ("c", "d")],
+        );
+        assert_eq!(
+            render(&ctx, "a", None),
+            "a v0.1.0\n\
+             ├── b v0.1.0\n\
+             │   └── c v0.1.0\n\
+             │       └── d v0.1.0\n\
+             └── c v0.1.0 (*)\n"
+        );
+    }
+
+    #[test]
+    fn crate_at_depth_limit_is_expanded_where_shallower() {
+        let ctx = context(
+            &["a", "b", "c", "d"],
+            &[("a", "b"), ("a", "c"), ("b", "c"), ("c", "d")],
+        );
+        assert_eq!(
+            render(&ctx, "a", Some(2)),
+            "a v0.1.0\n\
+             ├── b v0.1.0\n\
+             │   └── c v0.1.0\n\
+             └── c v0.1.0\n\
+             \x20   └── d v0.1.0\n"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{Result, bail};
+use cargo_embassy_devtool::format_path;
+use cargo_embassy_devtool::types::{Context, DependencyKind};
+use serde::Serialize;
+
+use crate::cmd::{EdgeKind, OutputFormat, dependency_kinds, print_json};
+
+/// Show the dependency paths through which one crate depends on another
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// The depending crate.
+    #[arg(value_name = "FROM")]
+    pub from: String,
+
+    /// The crate depended on.
+    #[arg(value_name = "TO")]
+    pub to: String,
+
+    /// Only show one of the shortest paths.
+    #[arg(long)]
+    pub shortest: bool,
+
+    /// Show at most this many paths, shortest first.
+    #[arg(long, default_value_t = 20, conflicts_with = "shortest")]
+    pub limit: usize,
+
+    /// Dependency kinds to follow.
+    #[arg(long, value_delimiter = ',', default_values = ["normal", "build", "dev"])]
+    pub edges: Vec<EdgeKind>,
+}
+
+#[derive(Serialize)]
+struct WhyJson<'a> {
+    from: &'a str,
+    to: &'a str,
+    paths: Vec<Vec<StepJson<'a>>>,
+}
+
+#[derive(Serialize)]
+struct StepJson<'a> {
+    from: &'a str,
+    to: &'a str,
+    kind: DependencyKind,
+    target: Option<&'a str>,
+}
+
+pub fn run(ctx: &Context, args: Args, format: OutputFormat) -> Result<()> {
+    for name in [&args.from, &args.to] {
+        if !ctx.crates.contains_key(name) {
+            bail!("Crate '{}' not found", name);
+        }
+    }
+
+    if args.limit == 0 {
+        bail!("--limit must be at least 1");
+    }
+
+    let kinds = dependency_kinds(&args.edges);
+    let paths = if args.shortest {
+        ctx.shortest_dependency_path(&args.from, &args.to, &kinds)
+            .into_iter()
+            .collect()
+    } else {
+        ctx.dependency_paths(&args.from, &args.to, &kinds, args.limit)
+    };
+    if paths.is_empty() {
+        bail!("'{}' does not depend on '{}'", args.from, args.to);
+    }
+
+    if format == OutputFormat::Json {
+        let paths = paths
+            .iter()
+            .map(|path| {
+                let mut from = args.from.as_str();
+                path.iter()
+                    .map(|edge| {
+                        let step = StepJson {
+                            from,
+                            to: &edge.name,
+                            kind: edge.kind,
+                            target: edge.target.as_deref(),
+                        };
+                        from = &edge.name;
+                        step
+                    })
+                    .collect()
+            })
+            .collect();
+        return print_json(&WhyJson {
+            from: &args.from,
+            to: &args.to,
+            paths,
+        });
+    }
+
+    if !args.shortest && paths.len() >= args.limit {
+        println!(
+            "{} depends on {} through at least {} paths, the shortest are (see --limit):",
+            args.from,
+            args.to,
+            paths.len()
+        );
+    } else {
+        println!(
+            "{} depends on {} through {} path{}:",
+            args.from,
+            args.to,
+            paths.len(),
+            if paths.len() == 1 { "" } else { "s" }
+        );
+    }
+    for path in &paths {
+        println!("  {}", format_path(&args.from, path));
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+//! Repository-level configuration, read from `embassy-devtool.toml` in the repository root.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+
+pub const CONFIG_FILE: &str = "embassy-devtool.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub discovery: Discovery,
+}
+
+/// Rules for finding crates and deciding which dependencies are intra-repo.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Discovery {
+    /// Dependencies whose name starts with one of these are intra-repo.
+    pub prefixes: Vec<String>,
+    /// Dependencies with one of these names are intra-repo.
+    pub names: Vec<String>,
+    /// Treat every dependency with a `path` as intra-repo.
+    pub path_dependencies: bool,
+    /// Only look for crates in directories matching one of these globs. Empty means everywhere.
+    pub include: Vec<String>,
+    /// Never look for crates in directories matching one of these globs.
+    pub exclude: Vec<String>,
+    /// Additional directories to search for crates, relative to the repository root.
+    pub roots: Vec<PathBuf>,
+    /// Skip files ignored by git when discovering crates and checking files.
+    pub respect_gitignore: bool,
+    /// List files with `git ls-files` instead of walking the directories, if `respect_gitignore`
+    /// is set. Files in git submodules aren't listed.
+    pub git_ls_files: bool,
+
+    #[serde(skip)]
+    include_set: GlobSet,
+    #[serde(skip)]
+    exclude_set: GlobSet,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            prefixes: vec!["embassy-".to_string(), "cyw43".to_string()],
+            names: Vec::new(),
+            path_dependencies: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            roots: Vec::new(),
+            respect_gitignore: true,
+            git_ls_files: false,
+            include_set: GlobSet::empty(),
+            exclude_set: GlobSet::empty(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from `root`, falling back to the defaults if there is no config file.
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join(CONFIG_FILE);
+        let mut config: Config = if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            toml::from_str(&content)
+                .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))?
+        } else {
+            Config::default()
+        };
+
+        let discovery = &mut config.discovery;
+        discovery.include_set = build_glob_set(&discovery.include)?;
+        discovery.exclude_set = build_glob_set(&discovery.exclude)?;
+        Ok(config)
+    }
+}
+
+impl Discovery {
+    /// Directories to search for crates: the repository root followed by any extra roots.
+    pub fn roots(&self, repo_root: &Path) -> Vec<PathBuf> {
+        std::iter::once(repo_root.to_path_buf())
+            .chain(self.roots.iter().map(|r| repo_root.join(r)))
+            .collect()
+    }
+
+    /// Whether the directory `rel_path`, relative to the searched root, should be skipped entirely.
+    pub fn is_excluded(&self, rel_path: &Path) -> bool {
+        self.exclude_set.is_match(rel_path)
+    }
+
+    /// Whether a crate in the directory `rel_path`, relative to the searched root, should be discovered.
+    pub fn is_included(&self, rel_path: &Path) -> bool {
+        self.include.is_empty() || self.include_set.is_match(rel_path)
+    }
+
+    /// Whether the dependency `name`, declared as `value`, refers to a crate in this repository.
+    pub fn is_repo_dependency(&self, name: &str, value: &toml::Value) -> bool {
+        self.names.iter().any(|n| n == name)
+            || self.prefixes.iter().any(|p| name.starts_with(p.as_str()))
+            || (self.path_dependencies && value.get("path").is_some())
+    }
+}
+
+fn build_glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(
+            GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| anyhow!("Invalid glob '{}' in {}: {}", glob, CONFIG_FILE, e))?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn defaults_without_config_file() {
+        let tmp = TempDir::new("config-default");
+        let discovery = Config::load(&tmp.0).unwrap().discovery;
+        assert!(discovery.respect_gitignore);
+        assert!(!discovery.path_dependencies);
+        assert!(discovery.is_included(Path::new("embassy-time")));
+        assert!(!discovery.is_excluded(Path::new("embassy-time")));
+        assert!(discovery.is_repo_dependency("embassy-time", &toml::Value::from("0.4")));
+        assert!(discovery.is_repo_dependency("cyw43-pio", &toml::Value::from("0.4")));
+        assert!(!discovery.is_repo_dependency("heapless", &toml::Value::from("0.8")));
+        assert_eq!(discovery.roots(&tmp.0), vec![tmp.0.clone()]);
+    }
+
+    #[test]
+    fn include_and_exclude_globs() {
+        let tmp = TempDir::new("config-globs");
+        tmp.write(
+            CONFIG_FILE,
+            r#"
+[discovery]
+include = ["embassy-*", "examples/*"]
+exclude = ["examples/std", "**/fixtures"]
+roots = ["../other"]
+"#,
+        );
+        let discovery = Config::load(&tmp.0).unwrap().discovery;
+        assert!(discovery.is_included(Path::new("embassy-time")));
+        assert!(discovery.is_included(Path::new("examples/nrf")));
+        // `*` doesn't cross directory boundaries
+        assert!(!discovery.is_included(Path::new("examples/nrf/sub")));
+        assert!(!discovery.is_included(Path::new("cyw43")));
+        assert!(discovery.is_excluded(Path::new("examples/std")));
+        assert!(discovery.is_excluded(Path::new("embassy-time/tests/fixtures")));
+        assert!(!discovery.is_excluded(Path::new("examples/nrf")));
+        assert_eq!(
+            discovery.roots(&tmp.0),
+            vec![tmp.0.clone(), tmp.0.join("../other")]
+        );
+    }
+
+    #[test]
+    fn path_dependencies_and_names() {
+        let tmp = TempDir::new("config-deps");
+        tmp.write(
+            CONFIG_FILE,
+            r#"
+[discovery]
+prefixes = []
+names = ["cyw43"]
+path-dependencies = true
+"#,
+        );
+        let discovery = Config::load(&tmp.0).unwrap().discovery;
+        let path_dep: toml::Value = toml::from_str("path = \"../foo\"").unwrap();
+        let version_dep = toml::Value::from("0.4");
+        assert!(discovery.is_repo_dependency("foo", &path_dep));
+        assert!(discovery.is_repo_dependency("cyw43", &version_dep));
+        assert!(!discovery.is_repo_dependency("embassy-time", &version_dep));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let tmp = TempDir::new("config-unknown");
+        tmp.write(CONFIG_FILE, "[discovery]\nprefix = [\"embassy-\"]\n");
+        let err = Config::load(&tmp.0).unwrap_err();
+        assert!(err.to_string().contains("prefix"), "{err}");
+
+        tmp.write(CONFIG_FILE, "[build]\njobs = 4\n");
+        assert!(Config::load(&tmp.0).is_err());
+    }
+
+    #[test]
+    fn invalid_glob() {
+        let tmp = TempDir::new("config-glob");
+        tmp.write(CONFIG_FILE, "[discovery]\ninclude = [\"embassy-[\"]\n");
+        assert!(Config::load(&tmp.0).is_err());
+    }
+}
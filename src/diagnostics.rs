@@ -0,0 +1,358 @@
+//! Compiler diagnostics from cargo's JSON messages (`--message-format=json`).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// A message printed by cargo on stdout with `--message-format=json`.
+#[derive(Debug, Clone)]
+pub enum CargoMessage {
+    /// A diagnostic from the compiler.
+    Diagnostic(Diagnostic),
+    /// The end of a cargo command. `cargo batch` prints one after all of its steps.
+    BuildFinished { success: bool },
+    /// Messages that are only interesting to cargo, e.g. about built artifacts.
+    Other,
+}
+
+/// A compiler diagnostic, such as a warning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Name of the package being compiled.
+    pub package: String,
+    /// `error`, `warning`, ...
+    pub level: String,
+    /// Lint or error code, e.g. `unused_variables` or `E0308`.
+    pub code: Option<String>,
+    pub message: String,
+    /// Primary location of the diagnostic. Relative to the root passed to [`parse_message`] if
+    /// it's inside of it.
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    /// The diagnostic as the compiler would print it.
+    pub rendered: Option<String>,
+}
+
+/// What identifies a diagnostic across configs.
+pub type DiagnosticKey = (
+    String,
+    Option<String>,
+    String,
+    Option<PathBuf>,
+    usize,
+    usize,
+);
+
+impl Diagnostic {
+    pub fn key(&self) -> DiagnosticKey {
+        (
+            self.level.clone(),
+            self.code.clone(),
+            self.message.clone(),
+            self.file.clone(),
+            self.line,
+            self.column,
+        )
+    }
+
+    pub fn is_warning(&self) -> bool {
+        self.level == "warning"
+    }
+
+    /// Whether this is an error, including internal compiler errors.
+    pub fn is_error(&self) -> bool {
+        self.level.starts_with("error")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum RawMessage {
+    CompilerMessage {
+        package_id: String,
+        manifest_path: PathBuf,
+        message: RawDiagnostic,
+    },
+    BuildFinished {
+        success: bool,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct RawDiagnostic {
+    level: String,
+    code: Option<RawCode>,
+    message: String,
+    spans: Vec<RawSpan>,
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct RawSpan {
+    file_name: PathBuf,
+    is_primary: bool,
+    line_start: usize,
+    column_start: usize,
+}
+
+/// Parse a line printed by cargo on stdout, `None` if it isn't a JSON message.
+///
+/// File names are resolved against the package and the directories above it, as the compiler
+/// reports them relative to the workspace root, and then made relative to `root`.
+pub fn parse_message(line: &str, root: &Path) -> Option<CargoMessage> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let message = match serde_json::from_str(line).ok()? {
+        RawMessage::CompilerMessage {
+            package_id,
+            manifest_path,
+            message,
+        } => {
+            let span = message.spans.iter().find(|span| span.is_primary);
+            CargoMessage::Diagnostic(Diagnostic {
+                package: package_name(&package_id),
+                level: message.level,
+                code: message.code.map(|code| code.code),
+                message: message.message,
+                file: span.map(|span| resolve_file(&span.file_name, &manifest_path, root)),
+                line: span.map_or(0, |span| span.line_start),
+                column: span.map_or(0, |span| span.column_start),
+                rendered: message.rendered,
+            })
+        }
+        RawMessage::BuildFinished { success } => CargoMessage::BuildFinished { success },
+        RawMessage::Other => CargoMessage::Other,
+    };
+    Some(message)
+}
+
+/// Name of a package from its id, e.g. `path+file:///repo/embassy-a#0.1.0` or
+/// `registry+https://github.com/rust-lang/crates.io-index#log@0.4.22`.
+fn package_name(package_id: &str) -> String {
+    let (source, fragment) = package_id.rsplit_once('#').unwrap_or((package_id, ""));
+    match fragment.split_once('@') {
+        Some((name, _)) => name.to_string(),
+        // The name is left out if it's the same as the last path segment
+        None => source.rsplit('/').next().unwrap_or(source).to_string(),
+    }
+}
+
+fn resolve_file(file: &Path, manifest_path: &Path, root: &Path) -> PathBuf {
+    let file = manifest_path
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join(file))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| file.to_path_buf());
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let file = file.canonicalize().unwrap_or(file);
+    match file.strip_prefix(&root) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => file,
+    }
+}
+
+/// A diagnostic and the configs it was reported for.
+#[derive(Debug, Clone)]
+pub struct DiagnosticEntry {
+    pub diagnostic: Diagnostic,
+    /// Ids of the configs, see [`crate::build::step_id`].
+    pub configs: BTreeSet<String>,
+}
+
+/// Diagnostics of a build, deduplicated across configs.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    entries: BTreeMap<DiagnosticKey, DiagnosticEntry>,
+}
+
+impl Diagnostics {
+    /// Record `diagnostic` for the configs `configs`. Returns whether it wasn't seen before.
+    pub fn add(&mut self, diagnostic: Diagnostic, configs: &[&str]) -> bool {
+        let mut new = false;
+        let entry = self.entries.entry(diagnostic.key()).or_insert_with(|| {
+            new = true;
+            DiagnosticEntry {
+                diagnostic,
+                configs: BTreeSet::new(),
+            }
+        });
+        entry
+            .configs
+            .extend(configs.iter().map(|config| config.to_string()));
+        new
+    }
+
+    /// Unique diagnostics, ordered by level, code, message and location.
+    pub fn iter(&self) -> impl Iterator<Item = &DiagnosticEntry> {
+        self.entries.values()
+    }
+
+    /// Unique warnings.
+    pub fn warnings(&self) -> impl Iterator<Item = &DiagnosticEntry> {
+        self.iter().filter(|entry| entry.diagnostic.is_warning())
+    }
+
+    /// Unique errors, including internal compiler errors.
+    pub fn errors(&self) -> impl Iterator<Item = &DiagnosticEntry> {
+        self.iter().filter(|entry| entry.diagnostic.is_error())
+    }
+
+    /// Unique warnings reported for the config `config`.
+    pub fn warnings_of(&self, config: &str) -> impl Iterator<Item = &DiagnosticEntry> {
+        self.warnings()
+            .filter(move |entry| entry.configs.contains(config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn compiler_message(root: &Path, level: &str, code: Option<&str>, line: usize) -> String {
+        serde_json::json!({
+            "reason": "compiler-message",
+            "package_id": format!("path+file://{}/embassy-a#0.1.0", root.display()),
+            "manifest_path": root.join("embassy-a/Cargo.toml"),
+            "target": {"name": "embassy_a"},
+            "message": {
+                "$message_type": "diagnostic",
+                "level": level,
+                "code": code.map(|code| serde_json::json!({"code": code, "explanation": null})),
+                "message": "unused variable: `x`",
+                "spans": [
+                    {"file_name": "embassy-a/src/macros.rs", "is_primary": false, "line_start": 1, "column_start": 1},
+                    {"file_name": "embassy-a/src/lib.rs", "is_primary": true, "line_start": line, "column_start": 9},
+                ],
+                "children": [],
+                "rendered": format!("{}: unused variable: `x`\n", level),
+            },
+        })
+        .to_string()
+    }
+
+    fn diagnostic(root: &Path, level: &str, line: usize) -> Diagnostic {
+        match parse_message(
+            &compiler_message(root, level, Some("unused_variables"), line),
+            root,
+        ) {
+            Some(CargoMessage::Diagnostic(diagnostic)) => diagnostic,
+            other => panic!("expected a diagnostic, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_compiler_messages() {
+        let tmp = TempDir::new("diagnostics-parse");
+        tmp.write("embassy-a/Cargo.toml", "");
+        tmp.write("embassy-a/src/lib.rs", "");
+
+        let warning = diagnostic(&tmp.0, "warning", 3);
+        assert_eq!(
+            warning,
+            Diagnostic {
+                package: "embassy-a".to_string(),
+                level: "warning".to_string(),
+                code: Some("unused_variables".to_string()),
+                message: "unused variable: `x`".to_string(),
+                file: Some(PathBuf::from("embassy-a/src/lib.rs")),
+                line: 3,
+                column: 9,
+                rendered: Some("warning: unused variable: `x`\n".to_string()),
+            }
+        );
+        assert!(warning.is_warning());
+        assert!(!warning.is_error());
+
+        let line = compiler_message(&tmp.0, "error", None, 4);
+        let Some(CargoMessage::Diagnostic(error)) = parse_message(&line, &tmp.0) else {
+            panic!("expected a diagnostic");
+        };
+        assert!(error.is_error());
+        assert_eq!(error.code, None);
+    }
+
+    #[test]
+    fn parse_other_messages() {
+        let root = Path::new("/repo");
+        assert!(matches!(
+            parse_message(r#"{"reason":"build-finished","success":false}"#, root),
+            Some(CargoMessage::BuildFinished { success: false })
+        ));
+        assert!(matches!(
+            parse_message(
+                r#"{"reason":"compiler-artifact","package_id":"x","filenames":[]}"#,
+                root
+            ),
+            Some(CargoMessage::Other)
+        ));
+        assert!(matches!(
+            parse_message(r#"{"reason":"build-script-executed"}"#, root),
+            Some(CargoMessage::Other)
+        ));
+        assert!(parse_message("   Compiling embassy-a v0.1.0", root).is_none());
+        assert!(parse_message("{not json", root).is_none());
+    }
+
+    #[test]
+    fn package_names() {
+        assert_eq!(
+            package_name("path+file:///repo/embassy-a#0.1.0"),
+            "embassy-a"
+        );
+        assert_eq!(
+            package_name("path+file:///repo/time#embassy-time@0.4.0"),
+            "embassy-time"
+        );
+        assert_eq!(
+            package_name("registry+https://github.com/rust-lang/crates.io-index#log@0.4.22"),
+            "log"
+        );
+        // Old format
+        assert_eq!(package_name("embassy-a"), "embassy-a");
+    }
+
+    #[test]
+    fn deduplicate_across_configs() {
+        let tmp = TempDir::new("diagnostics-dedup");
+        tmp.write("embassy-a/src/lib.rs", "");
+
+        let mut diagnostics = Diagnostics::default();
+        assert!(diagnostics.add(diagnostic(&tmp.0, "warning", 3), &["embassy-a@host"]));
+        assert!(!diagnostics.add(
+            diagnostic(&tmp.0, "warning", 3),
+            &["embassy-a@thumbv6m-none-eabi"]
+        ));
+        assert!(!diagnostics.add(diagnostic(&tmp.0, "warning", 3), &["embassy-a@host"]));
+        // Another location is another diagnostic
+        assert!(diagnostics.add(diagnostic(&tmp.0, "warning", 5), &["embassy-a@host"]));
+        assert!(diagnostics.add(diagnostic(&tmp.0, "error", 3), &["embassy-a@host"]));
+
+        assert_eq!(diagnostics.iter().count(), 3);
+        assert_eq!(diagnostics.warnings().count(), 2);
+        assert_eq!(diagnostics.errors().count(), 1);
+        let first = diagnostics.warnings().next().unwrap();
+        assert_eq!(
+            first.configs.iter().map(String::as_str).collect::<Vec<_>>(),
+            ["embassy-a@host", "embassy-a@thumbv6m-none-eabi"]
+        );
+        assert_eq!(diagnostics.warnings_of("embassy-a@host").count(), 2);
+        assert_eq!(
+            diagnostics
+                .warnings_of("embassy-a@thumbv6m-none-eabi")
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,442 @@
+//! Discovery of the crates in a repository.
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+
+use crate::cache::{Cache, CachedManifest};
+use crate::config::{Config, Discovery};
+use crate::types::*;
+use crate::walk::files;
+use crate::workspace::{Workspace, Workspaces};
+
+/// Find all crates below the repository `root` and the extra roots from `config`.
+///
+/// Manifests that can't be turned into a crate are skipped and reported in `diagnostics`.
+/// Unchanged manifests are taken from `cache`, which is updated with the others.
+pub fn list_crates(
+    root: &Path,
+    config: &Config,
+    cache: &mut Cache,
+    diagnostics: &mut Vec<ManifestDiagnostic>,
+) -> Result<BTreeMap<CrateId, Crate>> {
+    let discovery = &config.discovery;
+    let mut crates = BTreeMap::new();
+    let mut workspaces = Workspaces::default();
+    // Crate directory -> directory of the crate it shadows
+    let mut shadows = HashMap::new();
+    let mut duplicates = Vec::new();
+    let mut seen = HashSet::new();
+    // Canonical paths of the manifests found so far, as roots may overlap
+    let mut walked = HashSet::new();
+    for search_root in discovery.roots(root) {
+        let rel_path = |path: &Path| {
+            path.strip_prefix(&search_root)
+                .unwrap_or(path)
+                .to_path_buf()
+        };
+        let manifests = files(&search_root, discovery, |path, is_dir| {
+            if is_dir {
+                path.file_name()
+                    .is_none_or(|name| !name.eq_ignore_ascii_case("target"))
+                    && !discovery.is_excluded(&rel_path(path))
+            } else {
+                path.file_name().is_some_and(|name| name == "Cargo.toml")
+            }
+        })?;
+        for cargo_toml in manifests {
+            let path = cargo_toml.parent().unwrap_or(&search_root).to_path_buf();
+            if !discovery.is_included(&rel_path(&path)) {
+                continue;
+            }
+            seen.insert(cargo_toml.clone());
+            if !walked.insert(canonical(&cargo_toml)) {
+                continue;
+            }
+
+            let manifest = match cache.get(&cargo_toml) {
+                Some(manifest) => manifest,
+                None => {
+                    let content = fs::read_to_string(&cargo_toml)?;
+                    match read_manifest(&path, &cargo_toml, &content, &mut workspaces, discovery) {
+                        Ok(manifest) => {
+                            cache.insert(&cargo_toml, &content, manifest.clone());
+                            manifest
+                        }
+                        Err(diagnostic) => {
+                            diagnostics.push(diagnostic);
+                            continue;
+                        }
+                    }
+                }
+            };
+            let CachedManifest::Crate {
+                krate,
+                shadows: shadowed,
+            } = manifest
+            else {
+                continue;
+            };
+
+            if let Some(shadowed) = shadowed {
+                shadows.insert(canonical(&path), canonical(&shadowed));
+            }
+
+            match crates.entry(krate.name.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(krate);
+                }
+                Entry::Occupied(mut entry) => {
+                    let existing = canonical(&entry.get().path);
+                    let new = canonical(&krate.path);
+                    if shadows.get(&new) == Some(&existing) {
+                        entry.insert(krate);
+                    } else if shadows.get(&existing) != Some(&new) {
+                        duplicates.push(format!(
+                            "Crate '{}' is defined in both {} and {}",
+                            krate.name,
+                            entry.get().path.display(),
+                            krate.path.display()
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    cache.retain(|path| seen.contains(path));
+
+    if !duplicates.is_empty() {
+        return Err(anyhow!(
+            "Found {} duplicate crates. Set `package.metadata.embassy.shadows` to the path of the other crate to pick one:\n{}",
+            duplicates.len(),
+            duplicates.join("\n")
+        ));
+    }
+    Ok(crates)
+}
+
+/// Turn the manifest at `cargo_toml`, with the given `content`, into a crate.
+fn read_manifest(
+    path: &Path,
+    cargo_toml: &Path,
+    content: &str,
+    workspaces: &mut Workspaces,
+    discovery: &Discovery,
+) -> Result<CachedManifest, ManifestDiagnostic> {
+    let manifest: toml::Table = toml::from_str(content)
+        .map_err(|e| ManifestDiagnostic::from_toml(cargo_toml, content, e))?;
+
+    let Some(package) = manifest.get("package") else {
+        // Virtual workspace manifests have no package
+        if manifest.contains_key("workspace") {
+            return Ok(CachedManifest::Ignored);
+        }
+        return Err(ManifestDiagnostic {
+            path: cargo_toml.to_path_buf(),
+            location: None,
+            message: "missing [package] or [workspace] section".to_string(),
+        });
+    };
+
+    // Skipped crates may contain anything else
+    let skip = package
+        .get("metadata")
+        .and_then(|m| m.get("embassy"))
+        .and_then(|e| e.get("skip"))
+        .and_then(|s| s.as_bool());
+    if skip == Some(true) {
+        return Ok(CachedManifest::Ignored);
+    }
+
+    let parsed: ParsedCrate = toml::from_str(content)
+        .map_err(|e| ManifestDiagnostic::from_toml(cargo_toml, content, e))?;
+    let shadows = parsed
+        .package
+        .metadata
+        .embassy
+        .shadows
+        .as_ref()
+        .map(|shadowed| path.join(shadowed));
+
+    let krate = resolve_crate(path.to_path_buf(), parsed, workspaces, discovery).map_err(|e| {
+        ManifestDiagnostic {
+            path: cargo_toml.to_path_buf(),
+            location: None,
+            message: e.to_string(),
+        }
+    })?;
+    Ok(CachedManifest::Crate { krate, shadows })
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Build a [`Crate`] from its parsed manifest, resolving fields inherited from the workspace.
+fn resolve_crate(
+    path: PathBuf,
+    parsed: ParsedCrate,
+    workspaces: &mut Workspaces,
+    discovery: &Discovery,
+) -> Result<Crate> {
+    let package = parsed.package;
+    let workspace = workspaces.find(&path, package.workspace.as_deref())?;
+    let workspace = workspace.as_deref();
+
+    let mut tables = vec![
+        (DependencyKind::Normal, None, parsed.dependencies),
+        (DependencyKind::Build, None, parsed.build_dependencies),
+        (DependencyKind::Dev, None, parsed.dev_dependencies),
+    ];
+    for (cfg, target) in parsed.target {
+        tables.push((
+            DependencyKind::Normal,
+            Some(cfg.clone()),
+            target.dependencies,
+        ));
+        tables.push((
+            DependencyKind::Build,
+            Some(cfg.clone()),
+            target.build_dependencies,
+        ));
+        tables.push((DependencyKind::Dev, Some(cfg), target.dev_dependencies));
+    }
+
+    let mut dependencies = Vec::new();
+    for (kind, target, table) in tables {
+        for (name, value) in table {
+            dependencies.push(dependency_edge(
+                &path,
+                workspace,
+                discovery,
+                kind,
+                target.clone(),
+                name,
+                value,
+            )?);
+        }
+    }
+
+    let mut configs = package.metadata.embassy.build;
+    if configs.is_empty() {
+        configs.push(BuildConfig::default())
+    }
+
+    Ok(Crate {
+        name: package.name,
+        version: match package.version {
+            Some(version) => version.resolve("version", workspace)?,
+            None => "0.0.0".to_string(),
+        },
+        path,
+        workspace: workspace.map(|w| w.root.clone()),
+        dependencies,
+        features: parsed.features,
+        configs,
+        publish: package.publish.resolve("publish", workspace)?.into(),
+        doc: package.metadata.embassy_docs.is_some(),
+    })
+}
+
+fn dependency_edge(
+    crate_dir: &Path,
+    workspace: Option<&Workspace>,
+    discovery: &Discovery,
+    kind: DependencyKind,
+    target: Option<String>,
+    key: String,
+    value: toml::Value,
+) -> Result<DependencyEdge> {
+    let value = Workspace::resolve_dependency(workspace, &key, value)?;
+    let parsed: ParsedDependency = value
+        .clone()
+        .try_into()
+        .map_err(|e| anyhow!("invalid dependency `{}`: {}", key, e))?;
+    // Invalid requirements are kept in the graph, but without a `version_req`
+    let parse_version =
+        |name: &str, version: &str, intra_repo: bool| match semver::VersionReq::parse(version) {
+            Ok(req) => Some(req),
+            Err(e) => {
+                if intra_repo {
+                    log::warn!(
+                        "{}: invalid version requirement '{}' of `{}` in [{}]: {}",
+                        crate_dir.join("Cargo.toml").display(),
+                        version,
+                        name,
+                        kind.section(),
+                        e
+                    );
+                }
+                None
+            }
+        };
+
+    let edge = match parsed {
+        ParsedDependency::Simple(version) => {
+            let intra_repo = discovery.is_repo_dependency(&key, &value);
+            DependencyEdge {
+                version_req: parse_version(&key, &version, intra_repo),
+                version: Some(version),
+                intra_repo,
+                name: key,
+                rename: None,
+                kind,
+                target,
+                path: None,
+                features: Vec::new(),
+                optional: false,
+                default_features: true,
+            }
+        }
+        ParsedDependency::Detailed(dep) => {
+            // Renamed dependencies are identified by their real package name
+            let (name, rename) = match dep.package {
+                Some(package) if package != key => (package, Some(key)),
+                _ => (key, None),
+            };
+            let intra_repo = discovery.is_repo_dependency(&name, &value);
+            let version_req = dep.version.as_deref().and_then(|version| {
+                parse_version(rename.as_deref().unwrap_or(&name), version, intra_repo)
+            });
+            DependencyEdge {
+                version: dep.version,
+                version_req,
+                intra_repo,
+                name,
+                rename,
+                kind,
+                target,
+                path: dep.path.map(|p| crate_dir.join(p)),
+                features: dep.features,
+                optional: dep.optional,
+                default_features: dep.default_features,
+            }
+        }
+    };
+    Ok(edge)
+}
+
+/// Parse the crate in `path` on its own, outside of repository discovery.
+pub fn parse_crate(path: &Path, discovery: &Discovery) -> Result<Crate> {
+    let cargo_toml = path.join("Cargo.toml");
+    let content = fs::read_to_string(&cargo_toml)?;
+    let parsed: ParsedCrate = toml::from_str(&content)
+        .map_err(|e| anyhow!("Failed to parse {}: {}", cargo_toml.display(), e))?;
+    resolve_crate(
+        path.to_path_buf(),
+        parsed,
+        &mut Workspaces::default(),
+        discovery,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn parse(tmp: &TempDir, manifest: &str) -> Result<Crate> {
+        tmp.write("embassy-a/Cargo.toml", manifest);
+        parse_crate(&tmp.0.join("embassy-a"), &Discovery::default())
+    }
+
+    fn list(tmp: &TempDir, roots: &[&str]) -> Result<BTreeMap<CrateId, Crate>> {
+        let mut config = Config::default();
+        config.discovery.roots = roots.iter().map(PathBuf::from).collect();
+        list_crates(&tmp.0, &config, &mut Cache::default(), &mut Vec::new())
+    }
+
+    fn manifest(name: &str, extra: &str) -> String {
+        format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n{extra}")
+    }
+
+    #[test]
+    fn missing_version_defaults_to_zero() {
+        let tmp = TempDir::new("discover-version");
+        let krate = parse(&tmp, "[package]\nname = \"embassy-a\"\n").unwrap();
+        assert_eq!(krate.version, "0.0.0");
+        assert!(krate.publish);
+    }
+
+    #[test]
+    fn publish_registries() {
+        let tmp = TempDir::new("discover-publish");
+        let manifest = "[package]\nname = \"embassy-a\"\nversion = \"0.1.0\"\n";
+
+        let krate = parse(&tmp, &format!("{manifest}publish = [\"my-registry\"]\n")).unwrap();
+        assert!(krate.publish);
+        let krate = parse(&tmp, &format!("{manifest}publish = []\n")).unwrap();
+        assert!(!krate.publish);
+        let krate = parse(&tmp, &format!("{manifest}publish = false\n")).unwrap();
+        assert!(!krate.publish);
+        assert!(parse(&tmp, &format!("{manifest}publish = \"yes\"\n")).is_err());
+    }
+
+    #[test]
+    fn inherited_publish_registries() {
+        let tmp = TempDir::new("discover-inherited-publish");
+        tmp.write(
+            "Cargo.toml",
+            "[workspace]\n\n[workspace.package]\nversion = \"0.2.0\"\npublish = []\n",
+        );
+        let krate = parse(
+            &tmp,
+            "[package]\nname = \"embassy-a\"\nversion.workspace = true\npublish.workspace = true\n",
+        )
+        .unwrap();
+        assert_eq!(krate.version, "0.2.0");
+        assert!(!krate.publish);
+    }
+
+    #[test]
+    fn duplicate_crates() {
+        let tmp = TempDir::new("discover-duplicates");
+        tmp.write("a/embassy-x/Cargo.toml", &manifest("embassy-x", ""));
+        tmp.write("b/embassy-x/Cargo.toml", &manifest("embassy-x", ""));
+        let err = list(&tmp, &[]).unwrap_err().to_string();
+        assert!(err.contains("Found 1 duplicate crates"), "{err}");
+        assert!(
+            err.contains(&format!(
+                "Crate 'embassy-x' is defined in both {} and {}",
+                tmp.0.join("a/embassy-x").display(),
+                tmp.0.join("b/embassy-x").display()
+            )),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn shadowing_crate_wins_in_either_order() {
+        let shadowing = "[package.metadata.embassy]\nshadows = \"../../{}/embassy-x\"\n";
+        // The shadowing crate is found after and before the one it shadows
+        for (shadowed, by) in [("a", "b"), ("b", "a")] {
+            let tmp = TempDir::new(&format!("discover-shadows-{by}"));
+            tmp.write(
+                format!("{shadowed}/embassy-x/Cargo.toml"),
+                &manifest("embassy-x", ""),
+            );
+            tmp.write(
+                format!("{by}/embassy-x/Cargo.toml"),
+                &manifest("embassy-x", &shadowing.replace("{}", shadowed)),
+            );
+            let crates = list(&tmp, &[]).unwrap();
+            assert_eq!(crates["embassy-x"].path, tmp.0.join(by).join("embassy-x"));
+        }
+    }
+
+    #[test]
+    fn overlapping_roots_are_not_duplicates() {
+        let tmp = TempDir::new("discover-overlapping");
+        tmp.write("sub/embassy-x/Cargo.toml", &manifest("embassy-x", ""));
+        tmp.write("embassy-y/Cargo.toml", &manifest("embassy-y", ""));
+        // Nested in the repository root, and the root itself again through another path
+        let crates = list(&tmp, &["sub", "sub/..", "sub/embassy-x"]).unwrap();
+        assert_eq!(
+            crates.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["embassy-x", "embassy-y"]
+        );
+    }
+}
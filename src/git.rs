@@ -0,0 +1,88 @@
+//! Helpers for querying git.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Result, bail};
+
+/// Run git in `path` and return its trimmed stdout.
+fn git(path: &Path, args: &[&str]) -> Result<String> {
+    Ok(git_output(path, args)?.trim().to_string())
+}
+
+/// Run git in `path` with `-z` output and return the NUL-separated paths it printed.
+///
+/// Unlike the default output, paths aren't quoted and may contain any character.
+fn git_paths(path: &Path, args: &[&str]) -> Result<Vec<PathBuf>> {
+    Ok(git_output(path, args)?
+        .split('\0')
+        .filter(|file| !file.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn git_output(path: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed in {}: {}",
+            args.join(" "),
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Working tree of the superproject if `path` is inside a git submodule.
+pub fn superproject(path: &Path) -> Result<Option<PathBuf>> {
+    let superproject = git(path, &["rev-parse", "--show-superproject-working-tree"])?;
+    Ok((!superproject.is_empty()).then(|| PathBuf::from(superproject)))
+}
+
+/// Files below `path` that are tracked by git or untracked but not ignored, relative to `path`.
+pub fn ls_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = git_paths(
+        path,
+        &[
+            "ls-files",
+            "-z",
+            "--cached",
+            "--others",
+            "--exclude-standard",
+        ],
+    )?;
+    // Unmerged files are listed once per stage
+    files.dedup();
+    Ok(files)
+}
+
+/// Files of the git repository containing `path` that changed since it diverged from `since`.
+///
+/// This includes uncommitted changes and untracked files that aren't ignored, so the result
+/// matches what a commit of the current working tree would change. Paths are absolute.
+pub fn changed_files(path: &Path, since: &str) -> Result<Vec<PathBuf>> {
+    let toplevel = PathBuf::from(git(path, &["rev-parse", "--show-toplevel"])?);
+    let base = git(path, &["merge-base", since, "HEAD"])?;
+    let changed = git_paths(path, &["diff", "-z", "--name-only", "--no-renames", &base])?;
+    let untracked = git_paths(
+        path,
+        &[
+            "ls-files",
+            "-z",
+            "--others",
+            "--exclude-standard",
+            "--full-name",
+        ],
+    )?;
+
+    Ok(changed
+        .into_iter()
+        .chain(untracked)
+        .map(|file| toplevel.join(file))
+        .collect())
+}
@@ -0,0 +1,682 @@
+//! Indexed adjacency structure over the crates of a [`Context`](crate::Context), for traversals
+//! that stay fast on thousands of crates.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::OnceLock;
+
+use fixedbitset::FixedBitSet;
+
+use crate::types::{Crate, CrateId, DependencyKind};
+
+/// A dependency of a crate on another, or of another on it, as an index into the graph.
+pub type Neighbor = (usize, DependencyKind);
+
+/// Crates numbered in name order, with their intra-repo dependencies.
+///
+/// Dependencies are deduplicated by crate and kind, so a crate depended on in both
+/// `[dependencies]` and a `[target.'cfg(..)'.dependencies]` table has a single normal edge.
+#[derive(Debug)]
+pub struct CrateGraph {
+    names: Vec<CrateId>,
+    index: HashMap<CrateId, usize>,
+    /// Sorted by crate, then kind.
+    dependencies: Vec<Vec<Neighbor>>,
+    /// Sorted by crate, then kind.
+    dependents: Vec<Vec<Neighbor>>,
+    /// All crates, dependencies before the crates depending on them as far as cycles allow.
+    topological: Vec<usize>,
+    /// Memoized closures of every crate, per set of followed kinds (see [`kind_mask`]).
+    dependency_closures: Vec<Vec<OnceLock<FixedBitSet>>>,
+    dependent_closures: Vec<Vec<OnceLock<FixedBitSet>>>,
+}
+
+impl CrateGraph {
+    pub fn new(crates: &BTreeMap<CrateId, Crate>) -> Self {
+        let names: Vec<CrateId> = crates.keys().cloned().collect();
+        let index: HashMap<CrateId, usize> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), i))
+            .collect();
+
+        let mut dependencies = vec![Vec::new(); names.len()];
+        let mut dependents = vec![Vec::new(); names.len()];
+        for (i, krate) in crates.values().enumerate() {
+            for edge in krate.intra_repo_edges() {
+                if let Some(&j) = index.get(&edge.name) {
+                    dependencies[i].push((j, edge.kind));
+                    dependents[j].push((i, edge.kind));
+                }
+            }
+        }
+        for adjacent in dependencies.iter_mut().chain(dependents.iter_mut()) {
+            adjacent.sort_unstable();
+            adjacent.dedup();
+        }
+
+        let closures = || {
+            (0..KIND_MASKS)
+                .map(|_| (0..names.len()).map(|_| OnceLock::new()).collect())
+                .collect()
+        };
+        let mut graph = Self {
+            dependency_closures: closures(),
+            dependent_closures: closures(),
+            topological: Vec::with_capacity(names.len()),
+            names,
+            index,
+            dependencies,
+            dependents,
+        };
+        graph.topological = graph.topological_order();
+        graph
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.index.get(name).copied()
+    }
+
+    pub fn name(&self, index: usize) -> &CrateId {
+        &self.names[index]
+    }
+
+    /// Direct dependencies of the crate at `index` through edges of the given kinds, sorted by
+    /// crate and then kind.
+    pub fn dependencies<'a>(
+        &'a self,
+        index: usize,
+        kinds: &'a [DependencyKind],
+    ) -> impl Iterator<Item = Neighbor> + 'a {
+        filter_kinds(&self.dependencies[index], kinds)
+    }
+
+    /// Crates directly depending on the crate at `index` through edges of the given kinds,
+    /// sorted by crate and then kind.
+    pub fn dependents<'a>(
+        &'a self,
+        index: usize,
+        kinds: &'a [DependencyKind],
+    ) -> impl Iterator<Item = Neighbor> + 'a {
+        filter_kinds(&self.dependents[index], kinds)
+    }
+
+    /// All crates, dependencies first.
+    pub fn topological(&self) -> impl Iterator<Item = &CrateId> {
+        self.topological.iter().map(|&i| &self.names[i])
+    }
+
+    /// The crates in `set`, dependencies first.
+    pub fn in_topological_order<'a>(
+        &'a self,
+        set: &'a FixedBitSet,
+    ) -> impl Iterator<Item = &'a CrateId> {
+        self.topological
+            .iter()
+            .filter(|&&i| set.contains(i))
+            .map(|&i| &self.names[i])
+    }
+
+    /// The crate at `index` and all crates it recursively depends on through edges of the given
+    /// kinds.
+    pub fn dependency_closure(&self, index: usize, kinds: &[DependencyKind]) -> &FixedBitSet {
+        let mask = kind_mask(kinds);
+        closure(
+            index,
+            mask,
+            &self.dependencies,
+            &self.dependency_closures[mask],
+        )
+    }
+
+    /// The crate at `index` and all crates recursively depending on it through edges of the
+    /// given kinds.
+    pub fn dependent_closure(&self, index: usize, kinds: &[DependencyKind]) -> &FixedBitSet {
+        let mask = kind_mask(kinds);
+        closure(
+            index,
+            mask,
+            &self.dependents,
+            &self.dependent_closures[mask],
+        )
+    }
+
+    /// Strongly connected components formed by edges of the given kinds that contain a cycle,
+    /// i.e. have more than one crate or a crate depending on itself.
+    ///
+    /// Each component starts with the crate it was first entered through.
+    pub fn cycles(&self, kinds: &[DependencyKind]) -> Vec<Vec<usize>> {
+        const UNVISITED: usize = usize::MAX;
+        let mut index = vec![UNVISITED; self.len()];
+        let mut lowlink = vec![0; self.len()];
+        let mut on_stack = FixedBitSet::with_capacity(self.len());
+        let mut stack = Vec::new();
+        let mut next_index = 0;
+        let mut components = Vec::new();
+        // Tarjan's algorithm, with an explicit call stack of (crate, next dependency to visit)
+        let mut calls: Vec<(usize, usize)> = Vec::new();
+        for start in 0..self.len() {
+            if index[start] != UNVISITED {
+                continue;
+            }
+            calls.push((start, 0));
+            while let Some((i, next)) = calls.last_mut() {
+                let i = *i;
+                if index[i] == UNVISITED {
+                    index[i] = next_index;
+                    lowlink[i] = next_index;
+                    next_index += 1;
+                    stack.push(i);
+                    on_stack.insert(i);
+                }
+                if let Some(&(dep, kind)) = self.dependencies[i].get(*next) {
+                    *next += 1;
+                    if !kinds.contains(&kind) {
+                        continue;
+                    }
+                    if index[dep] == UNVISITED {
+                        calls.push((dep, 0));
+                    } else if on_stack.contains(dep) {
+                        lowlink[i] = lowlink[i].min(index[dep]);
+                    }
+                    continue;
+                }
+
+                calls.pop();
+                if let Some(&(parent, _)) = calls.last() {
+                    lowlink[parent] = lowlink[parent].min(lowlink[i]);
+                }
+                // `i` is the root of a component, pop it off the stack
+                if lowlink[i] == index[i] {
+                    let mut component = Vec::new();
+                    loop {
+                        let member = stack.pop().unwrap();
+                        on_stack.set(member, false);
+                        component.push(member);
+                        if member == i {
+                            break;
+                        }
+                    }
+                    component.reverse();
+                    components.push(component);
+                }
+            }
+        }
+
+        components
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1
+                    || self
+                        .dependencies(component[0], kinds)
+                        .any(|(dep, _)| dep == component[0])
+            })
+            .collect()
+    }
+
+    /// Group crates into layers following edges of the given kinds, see
+    /// [`Context::layers`](crate::Context::layers).
+    ///
+    /// Returns the layers, sorted by index, and a longest chain of dependencies with one crate
+    /// per layer, or `None` if the edges form a cycle.
+    pub fn layers(&self, kinds: &[DependencyKind]) -> Option<(Vec<Vec<usize>>, Vec<usize>)> {
+        let distinct = |neighbors: &mut dyn Iterator<Item = Neighbor>| {
+            let mut crates: Vec<usize> = neighbors.map(|(i, _)| i).collect();
+            crates.dedup();
+            crates
+        };
+        // Dependencies of each crate not placed in a layer yet
+        let mut remaining: Vec<usize> = (0..self.len())
+            .map(|i| distinct(&mut self.dependencies(i, kinds)).len())
+            .collect();
+        // The dependency in the previous layer that put each crate in its layer
+        let mut via: Vec<Option<usize>> = vec![None; self.len()];
+
+        let mut layers: Vec<Vec<usize>> = Vec::new();
+        let mut layer: Vec<usize> = (0..self.len()).filter(|&i| remaining[i] == 0).collect();
+        let mut placed = 0;
+        while !layer.is_empty() {
+            placed += layer.len();
+            let mut next = Vec::new();
+            for &i in &layer {
+                for dependent in distinct(&mut self.dependents(i, kinds)) {
+                    // The last dependency in name order among those in the latest layer
+                    via[dependent] = Some(i);
+                    remaining[dependent] -= 1;
+                    if remaining[dependent] == 0 {
+                        next.push(dependent);
+                    }
+                }
+            }
+            next.sort_unstable();
+            layers.push(std::mem::replace(&mut layer, next));
+        }
+        if placed < self.len() {
+            return None;
+        }
+
+        let mut critical_path = Vec::new();
+        let mut current = layers.last().and_then(|layer| layer.first()).copied();
+        while let Some(i) = current {
+            critical_path.push(i);
+            current = via[i];
+        }
+        critical_path.reverse();
+        Some((layers, critical_path))
+    }
+
+    /// A shortest chain of edges of the given kinds leading from `from` to `to`, as the crates
+    /// after `from` and the kinds of the edges to them.
+    ///
+    /// If `from` and `to` are the same crate, this is the shortest cycle through it.
+    pub fn shortest_path(
+        &self,
+        from: usize,
+        to: usize,
+        kinds: &[DependencyKind],
+    ) -> Option<Vec<Neighbor>> {
+        let mut parents: Vec<Option<(usize, DependencyKind)>> = vec![None; self.len()];
+        let mut queue = VecDeque::from([from]);
+        while let Some(i) = queue.pop_front() {
+            for (dep, kind) in self.dependencies(i, kinds) {
+                if dep == to {
+                    let mut path = vec![(dep, kind)];
+                    let mut current = i;
+                    while current != from {
+                        let (parent, kind) = parents[current].unwrap();
+                        path.push((current, kind));
+                        current = parent;
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                if dep != from && parents[dep].is_none() {
+                    parents[dep] = Some((i, kind));
+                    queue.push_back(dep);
+                }
+            }
+        }
+        None
+    }
+
+    /// Up to `limit` chains of edges of the given kinds leading from `from` to `to` that don't
+    /// pass through any crate twice, in the same form as [`Self::shortest_path`].
+    ///
+    /// Paths are returned shortest first, so the search stops early instead of enumerating all
+    /// of them, which can be exponentially many. Crates depended on through edges of different
+    /// kinds give one path per kind.
+    pub fn paths(
+        &self,
+        from: usize,
+        to: usize,
+        kinds: &[DependencyKind],
+        limit: usize,
+    ) -> Vec<Vec<Neighbor>> {
+        // Number of edges from each crate to `to`, to only explore crates that reach it in time
+        let mut distance = vec![usize::MAX; self.len()];
+        distance[to] = 0;
+        let mut queue = VecDeque::from([to]);
+        while let Some(i) = queue.pop_front() {
+            for (dependent, _) in self.dependents(i, kinds) {
+                if distance[dependent] == usize::MAX {
+                    distance[dependent] = distance[i] + 1;
+                    queue.push_back(dependent);
+                }
+            }
+        }
+
+        let mut search = PathSearch {
+            graph: self,
+            to,
+            kinds,
+            distance,
+            length: 0,
+            limit,
+            path: Vec::new(),
+            on_path: FixedBitSet::with_capacity(self.len()),
+            paths: Vec::new(),
+        };
+        search.on_path.insert(from);
+        // One pass per path length, as a path through a crate can't be longer than the graph
+        for length in 1..=self.len() {
+            if search.paths.len() >= limit {
+                break;
+            }
+            search.length = length;
+            search.visit(from);
+        }
+        search.paths
+    }
+
+    fn topological_order(&self) -> Vec<usize> {
+        let mut visited = FixedBitSet::with_capacity(self.len());
+        let mut order = Vec::with_capacity(self.len());
+        // Depth-first post-order, with an explicit stack of (crate, next dependency to visit)
+        let mut stack = Vec::new();
+        for start in 0..self.len() {
+            if visited.put(start) {
+                continue;
+            }
+            stack.push((start, 0));
+            while let Some((i, next)) = stack.last_mut() {
+                let i = *i;
+                if let Some(&(dep, _)) = self.dependencies[i].get(*next) {
+                    *next += 1;
+                    if !visited.put(dep) {
+                        stack.push((dep, 0));
+                    }
+                } else {
+                    order.push(i);
+                    stack.pop();
+                }
+            }
+        }
+        order
+    }
+}
+
+/// Number of distinct sets of dependency kinds, see [`kind_mask`].
+const KIND_MASKS: usize = 1 << 3;
+
+/// Bit set of `kinds`, used to memoize closures per set of kinds.
+fn kind_mask(kinds: &[DependencyKind]) -> usize {
+    kinds.iter().fold(0, |mask, kind| {
+        mask | match kind {
+            DependencyKind::Normal => 1,
+            DependencyKind::Build => 2,
+            DependencyKind::Dev => 4,
+        }
+    })
+}
+
+fn filter_kinds<'a>(
+    neighbors: &'a [Neighbor],
+    kinds: &'a [DependencyKind],
+) -> impl Iterator<Item = Neighbor> + 'a {
+    neighbors
+        .iter()
+        .copied()
+        .filter(|(_, kind)| kinds.contains(kind))
+}
+
+/// Everything reachable from `start` through `adjacent` edges of the kinds in `mask`, memoized
+/// in `closures`.
+fn closure<'a>(
+    start: usize,
+    mask: usize,
+    adjacent: &[Vec<Neighbor>],
+    closures: &'a [OnceLock<FixedBitSet>],
+) -> &'a FixedBitSet {
+    closures[start].get_or_init(|| {
+        let mut reached = FixedBitSet::with_capacity(adjacent.len());
+        reached.insert(start);
+        let mut stack = vec![start];
+        while let Some(i) = stack.pop() {
+            for &(next, kind) in &adjacent[i] {
+                if mask & kind_mask(&[kind]) == 0 || reached.contains(next) {
+                    continue;
+                }
+                // Reuse closures computed before instead of walking them again
+                if let Some(known) = closures[next].get() {
+                    reached.union_with(known);
+                } else {
+                    reached.insert(next);
+                    stack.push(next);
+                }
+            }
+        }
+        reached
+    })
+}
+
+/// State for the depth-first search in [`CrateGraph::paths`].
+struct PathSearch<'a> {
+    graph: &'a CrateGraph,
+    to: usize,
+    kinds: &'a [DependencyKind],
+    /// Number of edges from each crate to `to`, `usize::MAX` if it doesn't reach it.
+    distance: Vec<usize>,
+    /// Length of the paths searched for in this pass.
+    length: usize,
+    limit: usize,
+    path: Vec<Neighbor>,
+    on_path: FixedBitSet,
+    paths: Vec<Vec<Neighbor>>,
+}
+
+impl PathSearch<'_> {
+    fn visit(&mut self, i: usize) {
+        let depth = self.path.len() + 1;
+        for (dep, kind) in self.graph.dependencies(i, self.kinds) {
+            if self.paths.len() >= self.limit {
+                return;
+            }
+            if dep == self.to {
+                if depth == self.length {
+                    let mut found = self.path.clone();
+                    found.push((dep, kind));
+                    self.paths.push(found);
+                }
+            } else if self.distance[dep] <= self.length - depth && !self.on_path.put(dep) {
+                self.path.push((dep, kind));
+                self.visit(dep);
+                self.path.pop();
+                self.on_path.set(dep, false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::crates;
+    use DependencyKind::{Build, Dev, Normal};
+
+    fn names(graph: &CrateGraph, crates: impl IntoIterator<Item = usize>) -> Vec<&str> {
+        crates.into_iter().map(|i| graph.name(i).as_str()).collect()
+    }
+
+    #[test]
+    fn dependencies_are_deduplicated_by_crate_and_kind() {
+        let graph = CrateGraph::new(&crates(
+            &["a", "b"],
+            &[("a", "b", Normal), ("a", "b", Normal), ("a", "b", Dev)],
+        ));
+        let a = graph.index("a").unwrap();
+        let b = graph.index("b").unwrap();
+        assert_eq!(
+            graph
+                .dependencies(a, &DependencyKind::ALL)
+                .collect::<Vec<_>>(),
+            [(b, Normal), (b, Dev)]
+        );
+        assert_eq!(graph.dependents(b, &[Dev]).collect::<Vec<_>>(), [(a, Dev)]);
+    }
+
+    #[test]
+    fn closures_follow_only_the_given_kinds() {
+        let graph = CrateGraph::new(&crates(
+            &["a", "b", "c", "d"],
+            &[("a", "b", Normal), ("b", "c", Dev), ("c", "d", Build)],
+        ));
+        let a = graph.index("a").unwrap();
+        let d = graph.index("d").unwrap();
+        let closure = |set: &FixedBitSet| names(&graph, set.ones());
+
+        assert_eq!(closure(graph.dependency_closure(a, &[Normal])), ["a", "b"]);
+        assert_eq!(
+            closure(graph.dependency_closure(a, &DependencyKind::ALL)),
+            ["a", "b", "c", "d"]
+        );
+        // Memoized per set of kinds
+        assert_eq!(closure(graph.dependency_closure(a, &[Normal])), ["a", "b"]);
+        assert_eq!(closure(graph.dependent_closure(d, &[Build])), ["c", "d"]);
+        assert_eq!(
+            closure(graph.dependent_closure(d, &[Build, Dev, Normal])),
+            ["a", "b", "c", "d"]
+        );
+    }
+
+    #[test]
+    fn topological_order_puts_dependencies_first() {
+        let graph = CrateGraph::new(&crates(
+            &["a", "b", "c"],
+            &[("a", "c", Normal), ("c", "b", Build)],
+        ));
+        assert_eq!(
+            graph.topological().map(String::as_str).collect::<Vec<_>>(),
+            ["b", "c", "a"]
+        );
+    }
+
+    #[test]
+    fn cycles_are_strongly_connected_components() {
+        let graph = CrateGraph::new(&crates(
+            &["a", "b", "c", "d", "e", "f"],
+            &[
+                ("a", "b", Normal),
+                ("b", "c", Normal),
+                ("c", "a", Build),
+                ("c", "d", Normal),
+                ("d", "e", Normal),
+                ("e", "d", Dev),
+                ("f", "f", Dev),
+            ],
+        ));
+        let cycles = |kinds: &[DependencyKind]| -> Vec<Vec<&str>> {
+            graph
+                .cycles(kinds)
+                .into_iter()
+                .map(|component| names(&graph, component))
+                .collect()
+        };
+
+        assert_eq!(cycles(&[Normal]), Vec::<Vec<&str>>::new());
+        assert_eq!(cycles(&[Normal, Build]), [["a", "b", "c"]]);
+        assert_eq!(
+            cycles(&DependencyKind::ALL),
+            [vec!["d", "e"], vec!["a", "b", "c"], vec!["f"]]
+        );
+    }
+
+    #[test]
+    fn layers_and_critical_path() {
+        let graph = CrateGraph::new(&crates(
+            &["a", "b", "c", "d", "e"],
+            &[
+                ("b", "a", Normal),
+                ("c", "a", Normal),
+                ("d", "b", Normal),
+                ("d", "c", Normal),
+                ("e", "a", Dev),
+            ],
+        ));
+        let (layers, critical_path) = graph.layers(&[Normal]).unwrap();
+        let layers: Vec<_> = layers.into_iter().map(|l| names(&graph, l)).collect();
+        assert_eq!(layers, [vec!["a", "e"], vec!["b", "c"], vec!["d"]]);
+        // Through the last dependency in name order
+        assert_eq!(names(&graph, critical_path), ["a", "c", "d"]);
+
+        let (layers, _) = graph.layers(&DependencyKind::ALL).unwrap();
+        assert_eq!(names(&graph, layers[1].clone()), ["b", "c", "e"]);
+    }
+
+    #[test]
+    fn layers_fail_on_cycles() {
+        let graph = CrateGraph::new(&crates(
+            &["a", "b", "c"],
+            &[("a", "b", Normal), ("b", "a", Normal), ("c", "a", Normal)],
+        ));
+        assert!(graph.layers(&[Normal]).is_none());
+    }
+
+    #[test]
+    fn shortest_path_and_cycle() {
+        let graph = CrateGraph::new(&crates(
+            &["a", "b", "c", "d"],
+            &[
+                ("a", "b", Normal),
+                ("b", "c", Normal),
+                ("c", "d", Normal),
+                ("a", "d", Dev),
+                ("d", "a", Build),
+            ],
+        ));
+        let index = |name| graph.index(name).unwrap();
+        let path = |from, to, kinds: &[DependencyKind]| {
+            graph
+                .shortest_path(index(from), index(to), kinds)
+                .map(|path| {
+                    path.into_iter()
+                        .map(|(i, kind)| (graph.name(i).as_str(), kind))
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        assert_eq!(path("a", "d", &DependencyKind::ALL), Some(vec![("d", Dev)]));
+        assert_eq!(
+            path("a", "d", &[Normal]),
+            Some(vec![("b", Normal), ("c", Normal), ("d", Normal)])
+        );
+        assert_eq!(
+            path("a", "a", &[Dev, Build]),
+            Some(vec![("d", Dev), ("a", Build)])
+        );
+        assert_eq!(path("d", "c", &[Normal]), None);
+    }
+
+    #[test]
+    fn paths_shortest_first_up_to_limit() {
+        let graph = CrateGraph::new(&crates(
+            &["a", "b", "c", "d", "e"],
+            &[
+                ("a", "b", Normal),
+                ("a", "c", Normal),
+                ("b", "c", Normal),
+                ("b", "d", Normal),
+                ("c", "d", Normal),
+                ("c", "d", Build),
+                ("a", "d", Dev),
+                ("d", "a", Normal),
+            ],
+        ));
+        let index = |name| graph.index(name).unwrap();
+        let paths = |from, to, limit| -> Vec<Vec<(&str, DependencyKind)>> {
+            graph
+                .paths(index(from), index(to), &DependencyKind::ALL, limit)
+                .into_iter()
+                .map(|path| {
+                    path.into_iter()
+                        .map(|(i, kind)| (graph.name(i).as_str(), kind))
+                        .collect()
+                })
+                .collect()
+        };
+
+        let all = paths("a", "d", 10);
+        assert_eq!(
+            all,
+            [
+                vec![("d", Dev)],
+                vec![("b", Normal), ("d", Normal)],
+                vec![("c", Normal), ("d", Normal)],
+                vec![("c", Normal), ("d", Build)],
+                vec![("b", Normal), ("c", Normal), ("d", Normal)],
+                vec![("b", Normal), ("c", Normal), ("d", Build)],
+            ]
+        );
+        // The limit keeps the shortest ones
+        assert_eq!(paths("a", "d", 3), all[..3]);
+        // Crates are never passed twice, but a path may lead back to where it started
+        assert_eq!(paths("a", "a", 1), [vec![("d", Dev), ("a", Normal)]]);
+        assert!(paths("d", "e", 10).is_empty());
+    }
+}
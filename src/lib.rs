@@ -0,0 +1,318 @@
+//! Traverse and operate on intra-repo Rust crate dependencies.
+//!
+//! This is the library behind `cargo embassy-devtool`. Load the crates of a repository with
+//! [`load_context`], then query the dependency graph through [`Context`].
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::discover::list_crates;
+
+pub mod affected;
+pub mod build;
+pub mod bump;
+pub mod cache;
+pub mod cargo;
+pub mod config;
+pub mod diagnostics;
+pub mod discover;
+pub mod git;
+pub mod graph;
+pub mod matrix;
+pub mod report;
+#[doc(hidden)]
+pub mod testutil;
+pub mod types;
+pub mod walk;
+pub mod workspace;
+
+pub use types::{Context, Crate, CrateId, DependencyEdge, DependencyKind, Layers};
+
+/// Options for [`load_context`].
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Root of the repository. Defaults to the git repository containing the current directory.
+    pub root: Option<PathBuf>,
+    /// Operate on the outermost git superproject if the root is inside a submodule.
+    pub superproject: bool,
+    /// Warn about and skip manifests that fail to parse instead of failing.
+    pub allow_invalid_manifests: bool,
+    /// Also look at files ignored by git, overriding `respect-gitignore` in the config.
+    pub no_gitignore: bool,
+    /// Parse all manifests instead of reusing the results cached by a previous run.
+    pub no_cache: bool,
+}
+
+/// Find the root of the git repository containing the current directory.
+pub fn find_repo_root() -> Result<PathBuf> {
+    find_repo_root_from(&std::env::current_dir()?)
+}
+
+/// Find the root of the git repository containing `dir`.
+fn find_repo_root_from(dir: &Path) -> Result<PathBuf> {
+    let mut path = dir.canonicalize()?;
+
+    loop {
+        // Check if this directory contains a .git directory
+        if path.join(".git").exists() {
+            return Ok(path);
+        }
+
+        // Move to parent directory
+        match path.parent() {
+            Some(parent) => path = parent.to_path_buf(),
+            None => break,
+        }
+    }
+
+    Err(anyhow!(
+        "Could not find repository root. Make sure you're running this tool from within the embassy repository."
+    ))
+}
+
+/// Determine the root of the repository to operate on.
+pub fn repo_root(options: &LoadOptions) -> Result<PathBuf> {
+    let mut root = match &options.root {
+        Some(root) => root
+            .canonicalize()
+            .map_err(|e| anyhow!("Invalid repository root {}: {}", root.display(), e))?,
+        None => find_repo_root()?,
+    };
+
+    if options.superproject {
+        while let Some(superproject) = git::superproject(&root)? {
+            root = superproject;
+        }
+    } else if root.join(".git").is_file() {
+        // Submodules and worktrees have a `.git` file instead of a directory
+        if let Some(superproject) = git::superproject(&root)? {
+            log::info!(
+                "{} is a submodule of {}, pass --superproject to operate on the superproject instead",
+                root.display(),
+                superproject.display()
+            );
+        }
+    }
+
+    Ok(root)
+}
+
+/// Discover all crates in the repository and build their dependency graph.
+pub fn load_context(options: &LoadOptions) -> Result<Context> {
+    let root = repo_root(options)?;
+    let mut config = Config::load(&root)?;
+    if options.no_gitignore {
+        config.discovery.respect_gitignore = false;
+    }
+    let mut cache = if options.no_cache {
+        Cache::default()
+    } else {
+        Cache::load(&root)
+    };
+    let mut diagnostics = Vec::new();
+    let crates = list_crates(&root, &config, &mut cache, &mut diagnostics)?;
+    if let Err(e) = cache.save() {
+        log::warn!(
+            "Failed to save cache {}: {}",
+            Cache::path(&root).display(),
+            e
+        );
+    }
+
+    if !diagnostics.is_empty() {
+        if !options.allow_invalid_manifests {
+            return Err(anyhow!(
+                "Found {} invalid manifests, pass --allow-invalid-manifests to skip them:\n{}",
+                diagnostics.len(),
+                diagnostics
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+        for diagnostic in &diagnostics {
+            log::warn!("Skipping invalid manifest {}", diagnostic);
+        }
+    }
+
+    let ctx = Context::new(root, config, crates);
+
+    check_dependency_cycles(&ctx)?;
+
+    // Check for publish dependency conflicts
+    check_publish_dependencies(&ctx)?;
+
+    Ok(ctx)
+}
+
+/// Make the path "Windows"-safe
+pub fn windows_safe_path(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_str().unwrap().to_string().replace("\\\\?\\", ""))
+}
+
+/// Fail on cycles through normal and build dependencies, and warn about dev-dependency cycles.
+fn check_dependency_cycles(ctx: &Context) -> Result<()> {
+    let hard_kinds = [DependencyKind::Normal, DependencyKind::Build];
+    let cycles = ctx.cycles(&hard_kinds);
+    if !cycles.is_empty() {
+        return Err(anyhow!(
+            "Found {} dependency cycles:\n{}",
+            cycles.len(),
+            cycles
+                .iter()
+                .map(|cycle| format_cycle(ctx, cycle, &hard_kinds))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ));
+    }
+
+    for cycle in ctx.cycles(&DependencyKind::ALL) {
+        // A crate dev-depending on itself doesn't affect the publish order
+        if cycle.len() > 1 {
+            log::warn!(
+                "Dev-dependency cycle, these crates can't all be published in dependency order: {}",
+                format_cycle(ctx, &cycle, &DependencyKind::ALL)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Format a cycle as `a -> b -(dev)-> a`.
+pub(crate) fn format_cycle(ctx: &Context, cycle: &[CrateId], kinds: &[DependencyKind]) -> String {
+    format_path(&cycle[0], &ctx.cycle_path(cycle, kinds))
+}
+
+/// Format a chain of edges starting at `from` as `from -> b -(build)-> c`.
+pub fn format_path(from: &str, path: &[&DependencyEdge]) -> String {
+    let mut out = from.to_string();
+    for edge in path {
+        match edge.kind {
+            DependencyKind::Normal => out.push_str(" -> "),
+            DependencyKind::Build => out.push_str(" -(build)-> "),
+            DependencyKind::Dev => out.push_str(" -(dev)-> "),
+        }
+        out.push_str(&edge.name);
+    }
+    out
+}
+
+fn check_publish_dependencies(ctx: &Context) -> Result<()> {
+    for krate in ctx.crates.values() {
+        if krate.publish {
+            for dep_name in krate.edges_of_kind(DependencyKind::Normal).map(|e| &e.name) {
+                if let Some(dep_crate) = ctx.crates.get(dep_name)
+                    && !dep_crate.publish
+                {
+                    return Err(anyhow!(
+                        "Publishable crate '{}' depends on non-publishable crate '{}'. This is not allowed.",
+                        krate.name,
+                        dep_name
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use DependencyKind::{Build, Dev, Normal};
+
+    #[test]
+    fn dependency_paths_are_labeled_by_kind() {
+        let ctx = testutil::context(
+            &["a", "b", "c", "d"],
+            &[
+                ("d", "c", Normal),
+                ("c", "b", Build),
+                ("d", "b", Dev),
+                ("a", "b", Normal),
+            ],
+        );
+        let paths: Vec<String> = ctx
+            .dependency_paths("d", "b", &DependencyKind::ALL, 10)
+            .iter()
+            .map(|path| format_path("d", path))
+            .collect();
+        assert_eq!(paths, ["d -(dev)-> b", "d -> c -(build)-> b"]);
+
+        let shortest = ctx
+            .shortest_dependency_path("d", "b", &[Normal, Build])
+            .unwrap();
+        assert_eq!(format_path("d", &shortest), "d -> c -(build)-> b");
+
+        // Not through the kinds followed, and not at all
+        assert!(ctx.dependency_paths("d", "b", &[Normal], 10).is_empty());
+        assert!(ctx.shortest_dependency_path("d", "b", &[Normal]).is_none());
+        assert!(
+            ctx.dependency_paths("b", "d", &DependencyKind::ALL, 10)
+                .is_empty()
+        );
+        assert!(
+            ctx.dependency_paths("a", "d", &DependencyKind::ALL, 10)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn repo_root_from_subdirectory() {
+        let tmp = testutil::TempDir::new("repo-root");
+        tmp.write(".git/HEAD", "");
+        tmp.write("embassy-a/src/lib.rs", "");
+        let root = tmp.0.canonicalize().unwrap();
+
+        assert_eq!(
+            find_repo_root_from(&tmp.0.join("embassy-a/src")).unwrap(),
+            root
+        );
+        assert_eq!(find_repo_root_from(&tmp.0).unwrap(), root);
+        // Submodules and worktrees have a `.git` file
+        tmp.write("sub/.git", "gitdir: ../.git/modules/sub\n");
+        assert_eq!(
+            find_repo_root_from(&tmp.0.join("sub")).unwrap(),
+            root.join("sub")
+        );
+    }
+
+    #[test]
+    fn no_repo_root() {
+        let tmp = testutil::TempDir::new("no-repo-root");
+        tmp.write("embassy-a/Cargo.toml", "");
+        // Unless the temporary directory is inside a git checkout itself
+        if tmp.0.ancestors().all(|dir| !dir.join(".git").exists()) {
+            let err = find_repo_root_from(&tmp.0.join("embassy-a")).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with("Could not find repository root"),
+                "{err}"
+            );
+        }
+
+        // An explicit root is taken as is, but has to exist
+        let options = LoadOptions {
+            root: Some(tmp.0.join("embassy-a")),
+            ..Default::default()
+        };
+        assert_eq!(
+            repo_root(&options).unwrap(),
+            tmp.0.join("embassy-a").canonicalize().unwrap()
+        );
+        let options = LoadOptions {
+            root: Some(tmp.0.join("embassy-b")),
+            ..Default::default()
+        };
+        let err = repo_root(&options).unwrap_err();
+        assert!(
+            err.to_string().starts_with("Invalid repository root"),
+            "{err}"
+        );
+    }
+}
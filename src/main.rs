@@ -1,16 +1,11 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
-use anyhow::{Result, anyhow};
-use clap::{Parser, Subcommand, crate_version};
+use anyhow::{Result, bail};
+use cargo_embassy_devtool::{LoadOptions, load_context};
+use clap::{Parser, Subcommand, ValueEnum, crate_version};
 use simple_logger::SimpleLogger;
-use types::{Context, *};
 
-mod bump;
-mod cargo;
 mod cmd;
-mod types;
 
 /// Tool to traverse and operate on intra-repo Rust crate dependencies
 #[derive(Parser, Debug)]
@@ -19,6 +14,30 @@ struct Args {
     /// Command to perform on each crate
     #[command(subcommand)]
     command: Command,
+
+    /// Root of the repository to operate on. Defaults to the git repository containing the current directory
+    #[arg(long, global = true, env = "EMBASSY_DEVTOOL_ROOT")]
+    root: Option<PathBuf>,
+
+    /// Operate on the outermost git superproject when the repository is a submodule
+    #[arg(long, global = true)]
+    superproject: bool,
+
+    /// Warn about and skip manifests that fail to parse instead of failing
+    #[arg(long, global = true)]
+    allow_invalid_manifests: bool,
+
+    /// Also discover crates and check files that are ignored by git
+    #[arg(long, global = true)]
+    no_gitignore: bool,
+
+    /// Output format of the query commands
+    #[arg(long, global = true, value_enum, default_value_t = cmd::OutputFormat::Text)]
+    format: cmd::OutputFormat,
+
+    /// Parse all manifests instead of reusing the results cached in `target/embassy-devtool`
+    #[arg(long, global = true)]
+    no_cache: bool,
 }
 
 #[derive(Debug, Subcommand)]
@@ -26,6 +45,11 @@ enum Command {
     List(cmd::list::Args),
     Dependencies(cmd::dependencies::Args),
     Dependents(cmd::dependents::Args),
+    Graph(cmd::graph::Args),
+    Why(cmd::why::Args),
+    Affected(cmd::affected::Args),
+    Layers(cmd::layers::Args),
+    Matrix(cmd::matrix::Args),
     Bump(cmd::bump::Args),
     Build(cmd::build::Args),
     Check(cmd::build::Args),
@@ -36,124 +60,25 @@ enum Command {
     Doc(cmd::doc::Args),
 }
 
-fn list_crates(root: &PathBuf) -> Result<BTreeMap<CrateId, Crate>> {
-    let mut crates = BTreeMap::new();
-    let wd = walkdir::WalkDir::new(root);
-    for entry in wd
-        .into_iter()
-        .filter_entry(|e| e.file_type().is_dir() && !e.file_name().eq_ignore_ascii_case("target"))
-    {
-        let entry = entry?;
-        let path = root.join(entry.path());
-        let cargo_toml = path.join("Cargo.toml");
-
-        if cargo_toml.exists() {
-            let content = fs::read_to_string(&cargo_toml)?;
-
-            // Try to parse as a crate, skip if it's a workspace
-            let parsed: Result<ParsedCrate, _> = toml::from_str(&content);
-            if let Ok(parsed) = parsed {
-                let id = parsed.package.name;
-
-                let metadata = &parsed.package.metadata.embassy;
-
-                if metadata.skip {
-                    continue;
-                }
-
-                let mut dependencies = Vec::new();
-                let mut dev_dependencies = Vec::new();
-                let mut build_dependencies = Vec::new();
-
-                for (k, _) in parsed.dependencies {
-                    if k.starts_with("embassy-") || k.starts_with("cyw43") {
-                        dependencies.push(k);
-                    }
-                }
-
-                for (k, _) in parsed.dev_dependencies {
-                    if k.starts_with("embassy-") || k.starts_with("cyw43") {
-                        dev_dependencies.push(k);
-                    }
-                }
-
-                for (k, _) in parsed.build_dependencies {
-                    if k.starts_with("embassy-") || k.starts_with("cyw43") {
-                        build_dependencies.push(k);
-                    }
-                }
-
-                let mut configs = metadata.build.clone();
-                if configs.is_empty() {
-                    configs.push(BuildConfig::default())
-                }
-
-                crates.insert(
-                    id.clone(),
-                    Crate {
-                        name: id,
-                        version: parsed.package.version,
-                        path,
-                        dependencies,
-                        dev_dependencies,
-                        build_dependencies,
-                        configs,
-                        publish: parsed.package.publish,
-                        doc: parsed.package.metadata.embassy_docs.is_some(),
-                    },
-                );
+impl Command {
+    /// Output formats the command supports. Text is always supported.
+    fn formats(&self) -> &'static [cmd::OutputFormat] {
+        match self {
+            Command::List(_)
+            | Command::Dependencies(_)
+            | Command::Dependents(_)
+            | Command::Why(_)
+            | Command::Affected(_)
+            | Command::Layers(_)
+            | Command::Matrix(_)
+            | Command::Graph(_) => &[cmd::OutputFormat::Text, cmd::OutputFormat::Json],
+            // Only the plan is printed as JSON
+            Command::Build(args) | Command::Check(args) if args.dry_run => {
+                &[cmd::OutputFormat::Text, cmd::OutputFormat::Json]
             }
+            _ => &[cmd::OutputFormat::Text],
         }
     }
-    Ok(crates)
-}
-
-fn find_repo_root() -> Result<PathBuf> {
-    let mut path = std::env::current_dir()?.canonicalize()?;
-
-    loop {
-        // Check if this directory contains a .git directory
-        if path.join(".git").exists() {
-            return Ok(path);
-        }
-
-        // Move to parent directory
-        match path.parent() {
-            Some(parent) => path = parent.to_path_buf(),
-            None => break,
-        }
-    }
-
-    Err(anyhow!(
-        "Could not find repository root. Make sure you're running this tool from within the embassy repository."
-    ))
-}
-
-fn load_context() -> Result<Context> {
-    let root = find_repo_root()?;
-    let crates = list_crates(&root)?;
-
-    let mut reverse_deps: HashMap<String, HashSet<String>> = HashMap::new();
-
-    for (crate_name, krate) in &crates {
-        for dep_name in krate.all_dependencies() {
-            reverse_deps
-                .entry(dep_name.clone())
-                .or_default()
-                .insert(crate_name.clone());
-        }
-    }
-
-    let ctx = Context {
-        root,
-        crates,
-        reverse_deps,
-    };
-
-    // Check for publish dependency conflicts
-    check_publish_dependencies(&ctx)?;
-
-    Ok(ctx)
 }
 
 #[derive(Debug, Parser)]
@@ -165,27 +90,61 @@ enum Cargo {
 }
 
 fn main() -> Result<()> {
-    println!("embassy-devtool version {}", crate_version!());
-
-    SimpleLogger::new().init().unwrap();
+    SimpleLogger::new()
+        .with_module_level("globset", log::LevelFilter::Warn)
+        .with_module_level("ignore", log::LevelFilter::Warn)
+        .init()
+        .unwrap();
     let Cargo::EmbassyDevtool(args) = Cargo::parse();
-    let mut ctx = load_context()?;
+    let format = args.format;
+    if !args.command.formats().contains(&format) {
+        bail!(
+            "This command doesn't support `--format {}`",
+            format.to_possible_value().unwrap().get_name()
+        );
+    }
+    // The banner would break JSON, DOT and Mermaid output
+    if format != cmd::OutputFormat::Json && !matches!(args.command, Command::Graph(_)) {
+        println!("embassy-devtool version {}", crate_version!());
+    }
+    let mut ctx = load_context(&LoadOptions {
+        root: args.root,
+        superproject: args.superproject,
+        allow_invalid_manifests: args.allow_invalid_manifests,
+        no_gitignore: args.no_gitignore,
+        no_cache: args.no_cache,
+    })?;
 
     match args.command {
         Command::List(args) => {
-            cmd::list::run(&ctx, args)?;
+            cmd::list::run(&ctx, args, format)?;
         }
         Command::Dependencies(args) => {
-            cmd::dependencies::run(&ctx, args)?;
+            cmd::dependencies::run(&ctx, args, format)?;
         }
         Command::Dependents(args) => {
-            cmd::dependents::run(&ctx, args)?;
+            cmd::dependents::run(&ctx, args, format)?;
+        }
+        Command::Graph(args) => {
+            cmd::graph::run(&ctx, args, format)?;
+        }
+        Command::Why(args) => {
+            cmd::why::run(&ctx, args, format)?;
+        }
+        Command::Affected(args) => {
+            cmd::affected::run(&ctx, args, format)?;
+        }
+        Command::Layers(args) => {
+            cmd::layers::run(&ctx, args, format)?;
+        }
+        Command::Matrix(args) => {
+            cmd::matrix::run(&ctx, args, format)?;
         }
         Command::Build(args) => {
-            cmd::build::run(&ctx, args)?;
+            cmd::build::run(&ctx, args, format)?;
         }
         Command::Check(args) => {
-            cmd::check::run(&ctx, args)?;
+            cmd::check::run(&ctx, args, format)?;
         }
         Command::Bump(args) => {
             cmd::bump::run(&mut ctx, args)?;
@@ -208,27 +167,3 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
-
-/// Make the path "Windows"-safe
-pub fn windows_safe_path(path: &Path) -> PathBuf {
-    PathBuf::from(path.to_str().unwrap().to_string().replace("\\\\?\\", ""))
-}
-
-fn check_publish_dependencies(ctx: &Context) -> Result<()> {
-    for krate in ctx.crates.values() {
-        if krate.publish {
-            for dep_name in &krate.dependencies {
-                if let Some(dep_crate) = ctx.crates.get(dep_name)
-                    && !dep_crate.publish
-                {
-                    return Err(anyhow!(
-                        "Publishable crate '{}' depends on non-publishable crate '{}'. This is not allowed.",
-                        krate.name,
-                        dep_name
-                    ));
-                }
-            }
-        }
-    }
-    Ok(())
-}
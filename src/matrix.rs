@@ -0,0 +1,314 @@
+//! Splitting a build plan into shards of similar cost, to run on separate CI runners.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::build::BuildBatch;
+
+/// One of `count` shards, parsed from `index/count` with `index` starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// Index of the shard, starting at 0.
+    pub index: usize,
+    pub count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("expected a shard like `1/4`, got `{}`", s))?;
+        let index: usize = index.trim().parse()?;
+        let count: usize = count.trim().parse()?;
+        if count == 0 || index == 0 || index > count {
+            bail!(
+                "shard `{}` must be between 1/{} and {}/{}",
+                s,
+                count,
+                count,
+                count
+            );
+        }
+        Ok(Self {
+            index: index - 1,
+            count,
+        })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index + 1, self.count)
+    }
+}
+
+/// Build times of previous runs, keyed by step id.
+///
+/// Read from a JSON file with a `results` array of `{"id": ..., "duration_secs": ...}` objects,
+/// such as the JSON report of `build`. Other fields are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    durations: HashMap<String, f64>,
+}
+
+#[derive(Deserialize)]
+struct HistoryFile {
+    results: Vec<HistoryEntry>,
+}
+
+#[derive(Deserialize)]
+struct HistoryEntry {
+    id: String,
+    duration_secs: Option<f64>,
+}
+
+impl History {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read build history {}", path.display()))?;
+        let file: HistoryFile = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse build history {}", path.display()))?;
+        Ok(Self {
+            durations: file
+                .results
+                .into_iter()
+                .filter_map(|entry| Some((entry.id, entry.duration_secs?)))
+                .collect(),
+        })
+    }
+
+    /// Cost of every step in `batches`: its previous duration in seconds if known.
+    ///
+    /// Unknown steps cost the average of the known ones, or 1 without any history, so the
+    /// number of configs is balanced instead.
+    fn costs(&self, batches: &[BuildBatch]) -> Vec<Vec<f64>> {
+        let known: Vec<f64> = batches
+            .iter()
+            .flat_map(|batch| &batch.steps)
+            .filter_map(|step| self.durations.get(&step.id).copied())
+            .collect();
+        let fallback = if known.is_empty() {
+            1.0
+        } else {
+            known.iter().sum::<f64>() / known.len() as f64
+        };
+        batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .steps
+                    .iter()
+                    .map(|step| self.durations.get(&step.id).copied().unwrap_or(fallback))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// The batches assigned to one shard by [`shard`].
+#[derive(Debug, Clone)]
+pub struct ShardPlan<'a> {
+    pub batches: Vec<BuildBatch<'a>>,
+    /// Estimated cost of the shard, in seconds if there was history for the steps.
+    pub cost: f64,
+}
+
+/// Split `batches` into `count` shards with similar total cost.
+///
+/// Batches are never split, as their steps share an environment and `build-std` settings and are
+/// built by one `cargo batch` invocation with one target directory. They are assigned largest
+/// first to the least loaded shard, and keep their plan order within a shard. So shards are only
+/// as balanced as the batches allow: a batch costing more than a shard's share ends up alone in
+/// its shard, and with fewer batches than shards some shards stay empty. The result only depends
+/// on the batches and history, so every CI job computes the same shards.
+pub fn shard<'a>(
+    batches: &[BuildBatch<'a>],
+    count: usize,
+    history: &History,
+) -> Vec<ShardPlan<'a>> {
+    let mut costs: Vec<(usize, f64)> = history
+        .costs(batches)
+        .into_iter()
+        .map(|step_costs| step_costs.iter().sum())
+        .enumerate()
+        .collect();
+    // Stable, so batches of equal cost keep plan order
+    costs.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut shards: Vec<(f64, Vec<usize>)> = vec![(0.0, Vec::new()); count];
+    for (batch, cost) in costs {
+        // The first of equally loaded shards, to stay deterministic
+        if let Some(shard) = shards.iter_mut().min_by(|a, b| a.0.total_cmp(&b.0)) {
+            shard.0 += cost;
+            shard.1.push(batch);
+        }
+    }
+
+    shards
+        .into_iter()
+        .map(|(cost, mut indices)| {
+            indices.sort();
+            ShardPlan {
+                batches: indices.into_iter().map(|i| batches[i].clone()).collect(),
+                cost,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::build::{BuildCommand, plan};
+    use crate::testutil::{self, TempDir};
+    use crate::types::{BuildConfig, Crate};
+
+    /// A crate with one config per entry of `envs`, each in its own batch unless envs repeat.
+    fn krate(name: &str, envs: &[&str]) -> Crate {
+        let mut krate = testutil::krate(name, &[]);
+        krate.configs = envs
+            .iter()
+            .enumerate()
+            .map(|(i, env)| BuildConfig {
+                features: vec![format!("f{}", i)],
+                env: BTreeMap::from([("ENV".to_string(), env.to_string())]),
+                ..Default::default()
+            })
+            .collect();
+        krate
+    }
+
+    fn history(durations: &[(&str, f64)]) -> History {
+        History {
+            durations: durations
+                .iter()
+                .map(|(id, duration)| (id.to_string(), *duration))
+                .collect(),
+        }
+    }
+
+    fn ids<'a>(shard: &'a ShardPlan) -> Vec<&'a str> {
+        shard
+            .batches
+            .iter()
+            .flat_map(|batch| &batch.steps)
+            .map(|step| step.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn parse_shard() {
+        assert_eq!(
+            "2/4".parse::<Shard>().unwrap(),
+            Shard { index: 1, count: 4 }
+        );
+        assert_eq!("4/4".parse::<Shard>().unwrap().to_string(), "4/4");
+        assert!("0/4".parse::<Shard>().is_err());
+        assert!("1/0".parse::<Shard>().is_err());
+        assert!("5/4".parse::<Shard>().is_err());
+        assert!("4".parse::<Shard>().is_err());
+        assert!("a/4".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn batches_stay_whole() {
+        let crates = [krate("a", &["x", "x", "x", "x"]), krate("b", &["y"])];
+        let batches = plan(&crates, None, BuildCommand::Build);
+        assert_eq!(batches.len(), 2);
+
+        // The first batch costs more than half, but isn't split
+        let shards = shard(&batches, 2, &History::default());
+        assert_eq!(
+            ids(&shards[0]),
+            ["a@host[f0]", "a@host[f1]", "a@host[f2]", "a@host[f3]"]
+        );
+        assert_eq!(ids(&shards[1]), ["b@host[f0]"]);
+        assert_eq!(shards[0].cost, 4.0);
+        assert_eq!(shards[1].cost, 1.0);
+    }
+
+    #[test]
+    fn balance_by_history() {
+        let crates = [krate("a", &["w", "x", "y", "z"])];
+        let batches = plan(&crates, None, BuildCommand::Build);
+        assert_eq!(batches.len(), 4);
+
+        let history = history(&[
+            ("a@host[f0]", 10.0),
+            ("a@host[f1]", 6.0),
+            ("a@host[f2]", 5.0),
+        ]);
+        // The missing config costs the average of 7
+        let shards = shard(&batches, 2, &history);
+        assert_eq!(ids(&shards[0]), ["a@host[f0]", "a@host[f2]"]);
+        assert_eq!(ids(&shards[1]), ["a@host[f1]", "a@host[f3]"]);
+        assert_eq!(shards[0].cost, 15.0);
+        assert_eq!(shards[1].cost, 13.0);
+    }
+
+    #[test]
+    fn without_history_configs_are_counted() {
+        let crates = [krate("a", &["w", "x", "y"]), krate("b", &["z", "z"])];
+        let batches = plan(&crates, None, BuildCommand::Build);
+        let shards = shard(&batches, 3, &History::default());
+        let costs: Vec<f64> = shards.iter().map(|shard| shard.cost).collect();
+        assert_eq!(costs, [2.0, 2.0, 1.0]);
+
+        // More shards than batches leaves some empty, batches are never split to fill them
+        let shards = shard(&batches, 6, &History::default());
+        assert_eq!(shards.iter().filter(|s| s.batches.is_empty()).count(), 2);
+    }
+
+    #[test]
+    fn deterministic() {
+        let crates = [
+            krate("a", &["v", "w", "x"]),
+            krate("b", &["x", "y", "z"]),
+            krate("c", &["v", "z"]),
+        ];
+        let batches = plan(&crates, None, BuildCommand::Build);
+        let first: Vec<Vec<String>> = shard(&batches, 3, &History::default())
+            .iter()
+            .map(|s| ids(s).into_iter().map(String::from).collect())
+            .collect();
+        for _ in 0..10 {
+            let again: Vec<Vec<String>> = shard(&batches, 3, &History::default())
+                .iter()
+                .map(|s| ids(s).into_iter().map(String::from).collect())
+                .collect();
+            assert_eq!(first, again);
+        }
+        // Every config is in exactly one shard
+        let mut all: Vec<_> = first.concat();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 8);
+    }
+
+    #[test]
+    fn load_history() {
+        let tmp = TempDir::new("matrix-history");
+        let path = tmp.write(
+            "report.json",
+            r#"{"name": "build", "results": [
+                {"id": "a@host", "duration_secs": 2.5, "status": "passed"},
+                {"id": "b@host", "duration_secs": null}
+            ]}"#,
+        );
+        let history = History::load(&path).unwrap();
+        assert_eq!(history.durations.get("a@host"), Some(&2.5));
+        assert!(!history.durations.contains_key("b@host"));
+
+        tmp.write("report.json", "[]");
+        assert!(History::load(&path).is_err());
+    }
+}
@@ -0,0 +1,446 @@
+//! Reports of build results for CI dashboards, as JUnit XML or JSON.
+
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context as _, Result, anyhow};
+use serde::Serialize;
+
+use crate::build::{BatchResult, BatchStatus, BuildBatch};
+use crate::cargo::strip_ansi;
+use crate::diagnostics::Diagnostics;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Junit,
+    Json,
+}
+
+/// Where to write a report, parsed from `junit=<path>` or `json=<path>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportOutput {
+    pub format: ReportFormat,
+    pub path: PathBuf,
+}
+
+impl FromStr for ReportOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (format, path) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected `junit=<path>` or `json=<path>`, got `{}`", s))?;
+        let format = match format {
+            "junit" => ReportFormat::Junit,
+            "json" => ReportFormat::Json,
+            _ => {
+                return Err(anyhow!(
+                    "unknown report format `{}`, expected `junit` or `json`",
+                    format
+                ));
+            }
+        };
+        Ok(Self {
+            format,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaseStatus {
+    Passed,
+    Failed,
+    /// Not built because another batch failed.
+    Skipped,
+    /// The batch failed, but it's unknown whether this config was the cause. Building with
+    /// `keep_going` finds out.
+    Error,
+}
+
+/// Result of building one config of one crate.
+#[derive(Debug, Clone, Serialize)]
+pub struct TestCase {
+    /// See [`crate::build::step_id`].
+    pub id: String,
+    #[serde(rename = "crate")]
+    pub krate: String,
+    pub group: Option<String>,
+    pub target: Option<String>,
+    pub features: Vec<String>,
+    /// Index of the batch in the plan.
+    pub batch: usize,
+    pub status: CaseStatus,
+    /// Time spent building the config.
+    ///
+    /// `cargo batch` doesn't time its steps, so unless the config was built on its own this is
+    /// the duration of the batch divided evenly between its steps. `None` if it wasn't built.
+    pub duration_secs: Option<f64>,
+    /// Measured duration of the whole batch, `None` if it wasn't started.
+    pub batch_duration_secs: Option<f64>,
+    /// Number of unique warnings the compiler reported while building the config.
+    pub warnings: usize,
+    /// Failure message, for failed and errored configs.
+    pub message: Option<String>,
+    /// Rendered compiler errors followed by the captured stderr, for failed and errored configs.
+    pub stderr: Option<String>,
+}
+
+/// Results of a `build` or `check` run, one [`TestCase`] per config.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// Name of the run, e.g. `build`.
+    pub name: String,
+    pub results: Vec<TestCase>,
+}
+
+impl Report {
+    /// Build the report of `batches` from their `results`, as returned by
+    /// [`crate::build::run_batches`].
+    pub fn new(
+        name: &str,
+        batches: &[BuildBatch],
+        results: &[BatchResult],
+        diagnostics: &Diagnostics,
+    ) -> Self {
+        let mut cases = Vec::new();
+        for (i, (batch, result)) in batches.iter().zip(results).enumerate() {
+            let batch_duration =
+                (result.status != BatchStatus::Skipped).then_some(result.duration.as_secs_f64());
+            for (j, step) in batch.steps.iter().enumerate() {
+                let (status, duration, error, errors, stderr) = match result.steps.get(j) {
+                    Some(step_result) => (
+                        &step_result.status,
+                        Some(step_result.duration.as_secs_f64()),
+                        CaseStatus::Failed,
+                        &step_result.errors,
+                        &step_result.stderr,
+                    ),
+                    None => (
+                        &result.status,
+                        batch_duration.map(|duration| duration / batch.steps.len() as f64),
+                        CaseStatus::Error,
+                        &result.errors,
+                        &result.stderr,
+                    ),
+                };
+                let (status, message) = match status {
+                    BatchStatus::Passed => (CaseStatus::Passed, None),
+                    BatchStatus::Failed(message) => (error, Some(message.clone())),
+                    BatchStatus::Skipped => (CaseStatus::Skipped, None),
+                };
+                let failed = matches!(status, CaseStatus::Failed | CaseStatus::Error);
+                cases.push(TestCase {
+                    id: step.id.clone(),
+                    krate: step.krate.name.clone(),
+                    group: step.config.group.clone(),
+                    target: step.config.target.clone(),
+                    features: step.config.features.clone(),
+                    batch: i,
+                    status,
+                    duration_secs: duration,
+                    batch_duration_secs: batch_duration,
+                    warnings: diagnostics.warnings_of(&step.id).count(),
+                    message,
+                    stderr: failed.then(|| strip_ansi(&format!("{}{}", errors, stderr))),
+                });
+            }
+        }
+        Self {
+            name: name.to_string(),
+            results: cases,
+        }
+    }
+
+    pub fn write(&self, output: &ReportOutput) -> Result<()> {
+        let content = match output.format {
+            ReportFormat::Junit => self.to_junit(),
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+        };
+        if let Some(parent) = output.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&output.path, content)
+            .with_context(|| format!("Failed to write report {}", output.path.display()))
+    }
+
+    /// JUnit XML with a test suite per batch and a test case per config.
+    pub fn to_junit(&self) -> String {
+        let count =
+            |cases: &[&TestCase], status| cases.iter().filter(|c| c.status == status).count();
+        let all: Vec<_> = self.results.iter().collect();
+        let batches = self
+            .results
+            .iter()
+            .map(|case| case.batch)
+            .max()
+            .map_or(0, |max| max + 1);
+        let suites: Vec<Vec<&TestCase>> = (0..batches)
+            .map(|i| self.results.iter().filter(|case| case.batch == i).collect())
+            .collect();
+        let time = |cases: &[&TestCase]| {
+            cases
+                .first()
+                .and_then(|case| case.batch_duration_secs)
+                .unwrap_or(0.0)
+        };
+
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        writeln!(
+            out,
+            "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+            xml_escape(&self.name),
+            all.len(),
+            count(&all, CaseStatus::Failed),
+            count(&all, CaseStatus::Error),
+            count(&all, CaseStatus::Skipped),
+            suites.iter().map(|suite| time(suite)).sum::<f64>(),
+        )
+        .unwrap();
+        for (i, suite) in suites.iter().enumerate() {
+            writeln!(
+                out,
+                "  <testsuite name=\"{} batch {}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+                xml_escape(&self.name),
+                i + 1,
+                suite.len(),
+                count(suite, CaseStatus::Failed),
+                count(suite, CaseStatus::Error),
+                count(suite, CaseStatus::Skipped),
+                time(suite),
+            )
+            .unwrap();
+            for case in suite {
+                write!(
+                    out,
+                    "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                    xml_escape(&case.krate),
+                    xml_escape(&case.id),
+                    case.duration_secs.unwrap_or(0.0)
+                )
+                .unwrap();
+                let element = match case.status {
+                    CaseStatus::Passed => {
+                        out.push_str("/>\n");
+                        continue;
+                    }
+                    CaseStatus::Skipped => {
+                        out.push_str(">\n      <skipped/>\n    </testcase>\n");
+                        continue;
+                    }
+                    CaseStatus::Failed => "failure",
+                    CaseStatus::Error => "error",
+                };
+                writeln!(
+                    out,
+                    ">\n      <{} message=\"{}\">{}</{}>\n    </testcase>",
+                    element,
+                    xml_escape(case.message.as_deref().unwrap_or_default()),
+                    xml_escape(case.stderr.as_deref().unwrap_or_default()),
+                    element
+                )
+                .unwrap();
+            }
+            out.push_str("  </testsuite>\n");
+        }
+        out.push_str("</testsuites>\n");
+        out
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Other control characters aren't allowed in XML 1.0
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::build::{BuildCommand, StepResult, plan};
+    use crate::testutil;
+    use crate::types::{BuildConfig, Crate};
+
+    fn krate(name: &str, targets: &[&str]) -> Crate {
+        let mut krate = testutil::krate(name, &[]);
+        krate.configs = targets
+            .iter()
+            .map(|target| BuildConfig {
+                target: Some(target.to_string()),
+                ..Default::default()
+            })
+            .collect();
+        krate
+    }
+
+    fn result(status: BatchStatus, stderr: &str, steps: Vec<StepResult>) -> BatchResult {
+        BatchResult {
+            status,
+            duration: Duration::from_secs(4),
+            stderr: stderr.to_string(),
+            errors: String::new(),
+            steps,
+        }
+    }
+
+    #[test]
+    fn parse_output() {
+        assert_eq!(
+            "junit=target/report.xml".parse::<ReportOutput>().unwrap(),
+            ReportOutput {
+                format: ReportFormat::Junit,
+                path: PathBuf::from("target/report.xml"),
+            }
+        );
+        // Only the first `=` separates the format
+        assert_eq!(
+            "json=a=b.json".parse::<ReportOutput>().unwrap(),
+            ReportOutput {
+                format: ReportFormat::Json,
+                path: PathBuf::from("a=b.json"),
+            }
+        );
+        assert!("report.xml".parse::<ReportOutput>().is_err());
+        assert!("xml=report.xml".parse::<ReportOutput>().is_err());
+    }
+
+    #[test]
+    fn escape_xml() {
+        assert_eq!(
+            xml_escape("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+        assert_eq!(xml_escape("line\n\tnext\x07\x00"), "line\n\tnext");
+    }
+
+    #[test]
+    fn junit_with_failures() {
+        let crates = [krate(
+            "embassy-a",
+            &["thumbv7em-none-eabi", "thumbv6m-none-eabi"],
+        )];
+        let batches = plan(&crates, None, BuildCommand::Build);
+        let results = [result(
+            BatchStatus::Failed("cargo failed".to_string()),
+            "",
+            vec![
+                StepResult {
+                    status: BatchStatus::Passed,
+                    duration: Duration::from_secs(1),
+                    stderr: String::new(),
+                    errors: String::new(),
+                },
+                StepResult {
+                    status: BatchStatus::Failed("cargo failed".to_string()),
+                    duration: Duration::from_secs(2),
+                    stderr: "\x1b[1m\x1b[31merror\x1b[0m: expected `<T>` & \"U\"".to_string(),
+                    errors: String::new(),
+                },
+            ],
+        )];
+        let report = Report::new("build", &batches, &results, &Diagnostics::default());
+        assert_eq!(report.results[0].status, CaseStatus::Passed);
+        assert_eq!(report.results[1].status, CaseStatus::Failed);
+        assert_eq!(
+            report.results[1].stderr.as_deref(),
+            Some("error: expected `<T>` & \"U\"")
+        );
+
+        let junit = report.to_junit();
+        assert!(junit.contains(
+            "<testsuites name=\"build\" tests=\"2\" failures=\"1\" errors=\"0\" skipped=\"0\" time=\"4.000\">"
+        ));
+        assert!(junit.contains(
+            "<testcase classname=\"embassy-a\" name=\"embassy-a@thumbv7em-none-eabi\" time=\"1.000\"/>"
+        ));
+        assert!(junit.contains(
+            "<failure message=\"cargo failed\">error: expected `&lt;T&gt;` &amp; &quot;U&quot;</failure>"
+        ));
+    }
+
+    #[test]
+    fn junit_skipped_and_errored() {
+        let crates = [
+            krate("embassy-a", &["thumbv7em-none-eabi"]),
+            krate("embassy-b", &["thumbv7em-none-eabi"]),
+        ];
+        let mut batches = plan(&crates, None, BuildCommand::Check);
+        // Two batches, as if their environments differed
+        let second = batches[0].steps.split_off(1);
+        batches.push(BuildBatch {
+            config: batches[0].config.clone(),
+            steps: second,
+        });
+        let results = [
+            result(BatchStatus::Failed("boom".to_string()), "oops", Vec::new()),
+            result(BatchStatus::Skipped, "", Vec::new()),
+        ];
+        let report = Report::new("check", &batches, &results, &Diagnostics::default());
+        assert_eq!(report.results[0].status, CaseStatus::Error);
+        assert_eq!(report.results[0].stderr.as_deref(), Some("oops"));
+        assert_eq!(report.results[1].status, CaseStatus::Skipped);
+        assert_eq!(report.results[1].duration_secs, None);
+
+        let junit = report.to_junit();
+        assert!(junit.contains("<testsuite name=\"check batch 1\""));
+        assert!(junit.contains("<error message=\"boom\">oops</error>"));
+        assert!(junit.contains("<skipped/>"));
+    }
+
+    #[test]
+    fn failed_step_contains_compiler_errors() {
+        let crates = [krate(
+            "embassy-a",
+            &["thumbv7em-none-eabi", "thumbv6m-none-eabi"],
+        )];
+        let batches = plan(&crates, None, BuildCommand::Build);
+        let error = "\x1b[1m\x1b[91merror[E0308]\x1b[0m: mismatched types\n --> src/lib.rs:3:5\n";
+        let mut batch = result(
+            BatchStatus::Failed("cargo failed".to_string()),
+            "error: could not compile `embassy-a`\n",
+            vec![
+                StepResult {
+                    status: BatchStatus::Passed,
+                    duration: Duration::from_secs(1),
+                    stderr: String::new(),
+                    errors: String::new(),
+                },
+                StepResult {
+                    status: BatchStatus::Failed("cargo failed".to_string()),
+                    duration: Duration::from_secs(2),
+                    stderr: "error: could not compile `embassy-a`\n".to_string(),
+                    errors: error.to_string(),
+                },
+            ],
+        );
+        batch.errors = error.to_string();
+
+        let report = Report::new("build", &batches, &[batch.clone()], &Diagnostics::default());
+        let expected = "error[E0308]: mismatched types\n --> src/lib.rs:3:5\nerror: could not compile `embassy-a`\n";
+        assert_eq!(report.results[0].stderr, None);
+        assert_eq!(report.results[1].stderr.as_deref(), Some(expected));
+        assert!(report.to_junit().contains("mismatched types"));
+
+        // Without the steps built one by one, every config of the batch gets its errors
+        batch.steps.clear();
+        let report = Report::new("build", &batches, &[batch], &Diagnostics::default());
+        assert_eq!(report.results[0].status, CaseStatus::Error);
+        assert_eq!(report.results[0].stderr.as_deref(), Some(expected));
+    }
+}
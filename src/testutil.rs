@@ -0,0 +1,91 @@
+//! Helpers shared by the unit tests of the library and the command line tool.
+//!
+//! Not part of the public API.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::types::{Context, Crate, CrateId, DependencyEdge, DependencyKind};
+
+/// An empty directory that is removed again when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("embassy-devtool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// Write `content` to `path` relative to the directory, creating parent directories.
+    pub fn write(&self, path: impl AsRef<Path>, content: &str) -> PathBuf {
+        let path = self.0.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An intra-repo dependency on `name`, without version, path or features.
+pub fn edge(name: &str, kind: DependencyKind) -> DependencyEdge {
+    DependencyEdge {
+        name: name.to_string(),
+        rename: None,
+        kind,
+        target: None,
+        version: None,
+        version_req: None,
+        path: None,
+        features: Vec::new(),
+        optional: false,
+        default_features: true,
+        intra_repo: true,
+    }
+}
+
+/// A publishable crate `name` at version 0.1.0 in the directory `name`, without build configs.
+pub fn krate(name: &str, dependencies: &[(&str, DependencyKind)]) -> Crate {
+    Crate {
+        name: name.to_string(),
+        version: "0.1.0".to_string(),
+        path: name.into(),
+        workspace: None,
+        dependencies: dependencies
+            .iter()
+            .map(|&(dep, kind)| edge(dep, kind))
+            .collect(),
+        features: BTreeMap::new(),
+        configs: Vec::new(),
+        publish: true,
+        doc: false,
+    }
+}
+
+/// Crates named `names`, depending on each other through `edges` of (from, to, kind).
+pub fn crates(names: &[&str], edges: &[(&str, &str, DependencyKind)]) -> BTreeMap<CrateId, Crate> {
+    names
+        .iter()
+        .map(|&name| {
+            let dependencies: Vec<_> = edges
+                .iter()
+                .filter(|(from, _, _)| *from == name)
+                .map(|&(_, to, kind)| (to, kind))
+                .collect();
+            (name.to_string(), krate(name, &dependencies))
+        })
+        .collect()
+}
+
+/// A context of [`crates`], rooted at an empty path.
+pub fn context(names: &[&str], edges: &[(&str, &str, DependencyKind)]) -> Context {
+    Context::new(PathBuf::new(), Default::default(), crates(names, edges))
+}
@@ -1,7 +1,14 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-use serde::Deserialize;
+use anyhow::{Result, bail};
+use fixedbitset::FixedBitSet;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::graph::{CrateGraph, Neighbor};
+use crate::workspace::Inheritable;
 
 #[derive(Debug, Deserialize)]
 pub struct ParsedCrate {
@@ -12,20 +19,56 @@ pub struct ParsedCrate {
     pub build_dependencies: BTreeMap<String, toml::Value>,
     #[serde(rename = "dev-dependencies", default)]
     pub dev_dependencies: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    /// `[target.'cfg(...)'.*dependencies]` tables, keyed by the cfg expression or target triple.
+    #[serde(default)]
+    pub target: BTreeMap<String, ParsedTarget>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ParsedTarget {
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, toml::Value>,
+    #[serde(rename = "build-dependencies", default)]
+    pub build_dependencies: BTreeMap<String, toml::Value>,
+    #[serde(rename = "dev-dependencies", default)]
+    pub dev_dependencies: BTreeMap<String, toml::Value>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ParsedPackage {
     pub name: String,
-    pub version: String,
+    /// Cargo defaults a missing version to `0.0.0`.
+    pub version: Option<Inheritable<String>>,
     #[serde(default = "default_publish")]
-    pub publish: bool,
+    pub publish: Inheritable<Publish>,
+    /// Explicit path to the workspace root.
+    pub workspace: Option<String>,
     #[serde(default)]
     pub metadata: Metadata,
 }
 
-fn default_publish() -> bool {
-    true
+fn default_publish() -> Inheritable<Publish> {
+    Inheritable::Value(Publish::Allowed(true))
+}
+
+/// `publish = false` or the registries a crate may be published to, e.g. `publish = ["my-registry"]`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Publish {
+    Allowed(bool),
+    Registries(Vec<String>),
+}
+
+impl From<Publish> for bool {
+    fn from(publish: Publish) -> bool {
+        match publish {
+            Publish::Allowed(allowed) => allowed,
+            // An empty list forbids publishing
+            Publish::Registries(registries) => !registries.is_empty(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -43,13 +86,15 @@ pub struct MetadataEmbassy {
     pub skip: bool,
     #[serde(default)]
     pub build: Vec<BuildConfig>,
+    /// Path to another crate with the same name that this crate takes precedence over.
+    pub shadows: Option<String>,
 }
 
 // used just to check for presence.
 #[derive(Debug, Deserialize, Default)]
 pub struct MetadataEmbassyDocs {}
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct BuildConfig {
     pub group: Option<String>,
     #[serde(default)]
@@ -63,133 +108,373 @@ pub struct BuildConfig {
     pub artifact_dir: Option<String>,
 }
 
+/// A dependency entry, either `foo = "1.0"` or `foo = { ... }`.
+#[derive(Debug, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a version string or a dependency table"
+)]
+pub enum ParsedDependency {
+    Simple(String),
+    Detailed(DetailedDependency),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DetailedDependency {
+    pub version: Option<String>,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub optional: bool,
+    #[serde(default = "default_true", alias = "default_features")]
+    pub default_features: bool,
+    pub package: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
 pub type CrateId = String;
 
-#[derive(Debug, Clone)]
+/// A manifest that could not be turned into a [`Crate`] during discovery.
+#[derive(Debug)]
+pub struct ManifestDiagnostic {
+    pub path: PathBuf,
+    /// 1-based line and column of the offending TOML, if known.
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl ManifestDiagnostic {
+    pub fn from_toml(path: &Path, content: &str, error: toml::de::Error) -> Self {
+        let location = error.span().map(|span| {
+            let before = &content[..span.start];
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            (line, column)
+        });
+        Self {
+            path: path.to_path_buf(),
+            location,
+            message: error.message().trim_end().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ManifestDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some((line, column)) = self.location {
+            write!(f, ":{line}:{column}")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyKind {
+    Normal,
+    Build,
+    Dev,
+}
+
+impl DependencyKind {
+    /// Every kind, for queries that follow all dependency edges.
+    pub const ALL: [DependencyKind; 3] = [
+        DependencyKind::Normal,
+        DependencyKind::Build,
+        DependencyKind::Dev,
+    ];
+
+    /// Name of the manifest table holding dependencies of this kind.
+    pub fn section(self) -> &'static str {
+        match self {
+            DependencyKind::Normal => "dependencies",
+            DependencyKind::Build => "build-dependencies",
+            DependencyKind::Dev => "dev-dependencies",
+        }
+    }
+}
+
+/// A dependency declared in a crate's manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DependencyEdge {
+    /// Name of the depended-on package.
+    pub name: CrateId,
+    /// The key used in the manifest when it differs from the package name,
+    /// e.g. `time` for `time = { package = "embassy-time" }`.
+    pub rename: Option<String>,
+    pub kind: DependencyKind,
+    /// The `cfg(...)` expression or target triple of a `[target.'...'.dependencies]` table.
+    pub target: Option<String>,
+    /// The version requirement as written in the manifest.
+    pub version: Option<String>,
+    /// The parsed `version`, `None` if it isn't a valid requirement.
+    pub version_req: Option<semver::VersionReq>,
+    /// Absolute path of a `path` dependency.
+    pub path: Option<PathBuf>,
+    pub features: Vec<String>,
+    pub optional: bool,
+    pub default_features: bool,
+    /// Whether the dependency refers to a crate in this repository.
+    pub intra_repo: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Crate {
     pub name: CrateId,
     pub version: String,
     pub path: PathBuf,
-    pub dependencies: Vec<CrateId>,
-    pub build_dependencies: Vec<CrateId>,
-    pub dev_dependencies: Vec<CrateId>,
+    /// Root directory of the Cargo workspace this crate belongs to, if any.
+    pub workspace: Option<PathBuf>,
+    /// All dependencies, including ones from outside the repository.
+    pub dependencies: Vec<DependencyEdge>,
+    pub features: BTreeMap<String, Vec<String>>,
     pub configs: Vec<BuildConfig>,
     pub publish: bool,
     pub doc: bool,
 }
 
+impl DependencyEdge {
+    /// The key of the dependency in the manifest, which is also its name in `dep:` features.
+    pub fn key(&self) -> &str {
+        self.rename.as_deref().unwrap_or(&self.name)
+    }
+}
+
 impl Crate {
+    /// Intra-repo dependencies of all kinds.
     pub fn all_dependencies(&self) -> impl Iterator<Item = &CrateId> {
-        self.dependencies
-            .iter()
-            .chain(self.build_dependencies.iter())
-            .chain(self.dev_dependencies.iter())
+        self.intra_repo_edges().map(|edge| &edge.name)
+    }
+
+    /// Intra-repo dependency edges of all kinds.
+    pub fn intra_repo_edges(&self) -> impl Iterator<Item = &DependencyEdge> {
+        self.dependencies.iter().filter(|edge| edge.intra_repo)
+    }
+
+    /// Intra-repo dependency edges of the given kind.
+    pub fn edges_of_kind(&self, kind: DependencyKind) -> impl Iterator<Item = &DependencyEdge> {
+        self.intra_repo_edges()
+            .filter(move |edge| edge.kind == kind)
     }
 }
 
+/// Crates grouped by dependency depth, see [`Context::layers`].
+#[derive(Debug, Clone)]
+pub struct Layers {
+    /// Each layer only depends on the layers before it. Crates are sorted by name.
+    pub layers: Vec<Vec<CrateId>>,
+    /// A longest chain of dependencies, one crate per layer, dependencies first.
+    pub critical_path: Vec<CrateId>,
+}
+
 #[derive(Debug)]
 pub struct Context {
     pub root: PathBuf,
+    pub config: Config,
+    /// All discovered crates. Their dependencies must not be changed, as the dependency graph
+    /// is built from them by [`Context::new`].
     pub crates: BTreeMap<CrateId, Crate>,
-    pub reverse_deps: HashMap<CrateId, HashSet<CrateId>>,
+    graph: CrateGraph,
 }
 
 impl Context {
+    pub fn new(root: PathBuf, config: Config, crates: BTreeMap<CrateId, Crate>) -> Self {
+        Self {
+            graph: CrateGraph::new(&crates),
+            root,
+            config,
+            crates,
+        }
+    }
+
+    /// Index of the intra-repo dependencies between `crates`, for fast traversal.
+    pub fn graph(&self) -> &CrateGraph {
+        &self.graph
+    }
+
+    /// All intra-repo dependency edges between crates in the context.
+    pub fn edges(&self) -> impl Iterator<Item = (&Crate, &DependencyEdge)> {
+        self.crates.values().flat_map(move |krate| {
+            krate
+                .intra_repo_edges()
+                .filter(|edge| self.crates.contains_key(&edge.name))
+                .map(move |edge| (krate, edge))
+        })
+    }
+
+    /// The given crates and all crates they recursively depend on, dependencies first.
     pub fn recursive_dependencies(
         &self,
         crates: impl Iterator<Item = impl AsRef<str>>,
     ) -> impl Iterator<Item = CrateId> {
-        let mut visited = Vec::new();
-        let mut stack = Vec::new();
-
-        // Initialize stack with input crates
-        for crate_name in crates {
-            let crate_name = crate_name.as_ref().to_string();
-            if !visited.contains(&crate_name) {
-                stack.push(crate_name.clone());
-                visited.push(crate_name.clone());
-            }
-        }
-
-        while let Some(crate_name) = stack.pop() {
-            if let Some(krate) = self.crates.get(&crate_name) {
-                for dep in krate.all_dependencies() {
-                    if !visited.contains(dep) {
-                        stack.push(dep.clone());
-                        visited.push(dep.clone());
-                    }
-                }
-            }
-        }
-
-        visited.into_iter()
+        self.closure(crates, CrateGraph::dependency_closure)
     }
 
+    /// The given crates and all crates recursively depending on them, dependencies first.
     pub fn recursive_dependents(
         &self,
         crates: impl Iterator<Item = impl AsRef<str>>,
     ) -> impl Iterator<Item = CrateId> {
-        let mut visited = Vec::new();
-        let mut stack = Vec::new();
+        self.closure(crates, CrateGraph::dependent_closure)
+    }
 
-        // Initialize stack with input crates
+    fn closure(
+        &self,
+        crates: impl Iterator<Item = impl AsRef<str>>,
+        closure: impl for<'a> Fn(&'a CrateGraph, usize, &[DependencyKind]) -> &'a FixedBitSet,
+    ) -> impl Iterator<Item = CrateId> {
+        let mut set = FixedBitSet::with_capacity(self.graph.len());
         for crate_name in crates {
-            let crate_name = crate_name.as_ref().to_string();
-            if !visited.contains(&crate_name) {
-                stack.push(crate_name.clone());
-                visited.push(crate_name.clone());
+            if let Some(index) = self.graph.index(crate_name.as_ref()) {
+                set.union_with(closure(&self.graph, index, &DependencyKind::ALL));
             }
         }
+        self.graph
+            .in_topological_order(&set)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
 
-        while let Some(crate_name) = stack.pop() {
-            if let Some(dependents) = self.reverse_deps.get(&crate_name) {
-                for dependent in dependents {
-                    if !visited.contains(dependent) {
-                        stack.push(dependent.clone());
-                        visited.push(dependent.clone());
-                    }
-                }
-            }
+    /// Group crates into layers that can be processed in parallel, following edges of the given
+    /// kinds.
+    ///
+    /// Crates in the first layer depend on no other crate, crates in later layers only on crates
+    /// in earlier ones. Fails if the edges form a cycle.
+    pub fn layers(&self, kinds: &[DependencyKind]) -> Result<Layers> {
+        let cycles = self.cycles(kinds);
+        if !cycles.is_empty() {
+            bail!(
+                "Crates can't be layered because of {} dependency cycles:\n{}",
+                cycles.len(),
+                cycles
+                    .iter()
+                    .map(|cycle| crate::format_cycle(self, cycle, kinds))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
         }
 
-        visited.into_iter()
+        let (layers, critical_path) = self
+            .graph
+            .layers(kinds)
+            .expect("crates without cycles can be layered");
+        let names = |crates: Vec<usize>| {
+            crates
+                .into_iter()
+                .map(|i| self.graph.name(i).clone())
+                .collect()
+        };
+        Ok(Layers {
+            layers: layers.into_iter().map(names).collect(),
+            critical_path: names(critical_path),
+        })
     }
 
+    /// All crates, dependencies before the crates depending on them as far as cycles allow.
     pub fn topological_sort(&self) -> Vec<CrateId> {
-        let mut visited = HashSet::new();
-        let mut result = Vec::new();
+        self.graph.topological().cloned().collect()
+    }
 
-        // Visit all crates
-        for crate_name in self.crates.keys() {
-            self.topological_sort_visit(crate_name, &mut visited, &mut result);
-        }
+    /// Intra-repo edges of the given kinds from `crate_name` to other crates in the context.
+    pub fn edges_of_kinds<'a>(
+        &'a self,
+        crate_name: &str,
+        kinds: &'a [DependencyKind],
+    ) -> impl Iterator<Item = &'a DependencyEdge> {
+        self.crates
+            .get(crate_name)
+            .into_iter()
+            .flat_map(|krate| krate.intra_repo_edges())
+            .filter(move |edge| kinds.contains(&edge.kind) && self.crates.contains_key(&edge.name))
+    }
 
-        result
+    /// Find dependency cycles formed by edges of the given kinds.
+    ///
+    /// Returns the strongly connected components of the graph that contain a cycle, i.e. have
+    /// more than one crate or a crate depending on itself.
+    pub fn cycles(&self, kinds: &[DependencyKind]) -> Vec<Vec<CrateId>> {
+        self.graph
+            .cycles(kinds)
+            .into_iter()
+            .map(|component| {
+                component
+                    .into_iter()
+                    .map(|i| self.graph.name(i).clone())
+                    .collect()
+            })
+            .collect()
     }
 
-    fn topological_sort_visit(
-        &self,
-        crate_name: &str,
-        visited: &mut HashSet<CrateId>,
-        result: &mut Vec<CrateId>,
-    ) {
-        if visited.contains(crate_name) {
-            return;
-        }
+    /// Find the shortest cycle through the first crate of a component returned by [`Self::cycles`].
+    ///
+    /// Returns the edges along the cycle, starting and ending at `component[0]`.
+    pub fn cycle_path<'a>(
+        &'a self,
+        component: &[CrateId],
+        kinds: &'a [DependencyKind],
+    ) -> Vec<&'a DependencyEdge> {
+        self.shortest_dependency_path(&component[0], &component[0], kinds)
+            .unwrap_or_default()
+    }
 
-        visited.insert(crate_name.to_string());
+    /// Find a shortest chain of edges of the given kinds leading from `from` to `to`.
+    ///
+    /// If `from` and `to` are the same crate, this is the shortest cycle through it.
+    pub fn shortest_dependency_path(
+        &self,
+        from: &str,
+        to: &str,
+        kinds: &[DependencyKind],
+    ) -> Option<Vec<&DependencyEdge>> {
+        let from = self.graph.index(from)?;
+        let path = self
+            .graph
+            .shortest_path(from, self.graph.index(to)?, kinds)?;
+        Some(self.path_edges(from, &path))
+    }
 
-        // Visit all dependencies first
-        if let Some(krate) = self.crates.get(crate_name) {
-            for dep in krate.all_dependencies() {
-                // Only visit dependencies that exist in our crate set
-                if self.crates.contains_key(dep) {
-                    self.topological_sort_visit(dep, visited, result);
-                }
-            }
-        }
+    /// Find up to `limit` chains of edges of the given kinds leading from `from` to `to` that
+    /// don't pass through any crate twice.
+    ///
+    /// Paths are returned shortest first. Crates depended on through edges of different kinds
+    /// give one path per kind.
+    pub fn dependency_paths(
+        &self,
+        from: &str,
+        to: &str,
+        kinds: &[DependencyKind],
+        limit: usize,
+    ) -> Vec<Vec<&DependencyEdge>> {
+        let (Some(from), Some(to)) = (self.graph.index(from), self.graph.index(to)) else {
+            return Vec::new();
+        };
+        self.graph
+            .paths(from, to, kinds, limit)
+            .iter()
+            .map(|path| self.path_edges(from, path))
+            .collect()
+    }
 
-        // Add current crate after all its dependencies
-        result.push(crate_name.to_string());
+    /// The edges along a path from `from` returned by the graph.
+    fn path_edges(&self, from: usize, path: &[Neighbor]) -> Vec<&DependencyEdge> {
+        let mut current = from;
+        path.iter()
+            .map(|&(next, kind)| {
+                let name = self.graph.name(next);
+                let edge = self.crates[self.graph.name(current)]
+                    .edges_of_kind(kind)
+                    .find(|edge| edge.name == *name)
+                    .expect("edges in the graph exist in the crates");
+                current = next;
+                edge
+            })
+            .collect()
     }
 }
@@ -0,0 +1,247 @@
+//! Walking the files of a repository.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use ignore::{WalkBuilder, WalkState};
+
+use crate::config::Discovery;
+use crate::git;
+
+/// List the files below `root`, skipping `.git` directories and paths rejected by `filter`.
+///
+/// `filter` is called with the path of every directory and file and whether it is a directory.
+/// Rejecting a directory skips everything below it. Which files are seen depends on `discovery`:
+///
+/// - With `respect_gitignore`, files ignored by `.gitignore`, `.git/info/exclude` and `.ignore`
+///   files are skipped. Hidden files are always included.
+/// - With `git_ls_files` as well, the files are listed by `git ls-files` instead of walking the
+///   directories: those tracked by git and untracked ones that aren't ignored. If `root` isn't in
+///   a git checkout, or git fails otherwise, the directories are walked after all.
+///
+/// The paths are sorted, and start with `root`.
+pub fn files<F>(root: &Path, discovery: &Discovery, filter: F) -> Result<Vec<PathBuf>>
+where
+    F: Fn(&Path, bool) -> bool + Sync,
+{
+    let mut files = if discovery.respect_gitignore && discovery.git_ls_files {
+        match git_files(root, &filter) {
+            Ok(files) => files,
+            Err(e) => {
+                log::warn!("Walking {} instead: {}", root.display(), e);
+                walk(root, true, &filter)?
+            }
+        }
+    } else {
+        walk(root, discovery.respect_gitignore, &filter)?
+    };
+    files.sort();
+    Ok(files)
+}
+
+fn walk<F>(root: &Path, respect_gitignore: bool, filter: &F) -> Result<Vec<PathBuf>>
+where
+    F: Fn(&Path, bool) -> bool + Sync,
+{
+    let files = Mutex::new(Vec::new());
+    let error = Mutex::new(None);
+    WalkBuilder::new(root)
+        .standard_filters(respect_gitignore)
+        .hidden(false)
+        .build_parallel()
+        .run(|| {
+            Box::new(|entry| {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        error.lock().unwrap().get_or_insert(e);
+                        return WalkState::Quit;
+                    }
+                };
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                if entry.depth() > 0
+                    && (entry.file_name() == ".git" || !filter(entry.path(), is_dir))
+                {
+                    return if is_dir {
+                        WalkState::Skip
+                    } else {
+                        WalkState::Continue
+                    };
+                }
+                if !is_dir {
+                    files.lock().unwrap().push(entry.into_path());
+                }
+                WalkState::Continue
+            })
+        });
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e.into());
+    }
+    Ok(files.into_inner().unwrap())
+}
+
+fn git_files<F>(root: &Path, filter: &F) -> Result<Vec<PathBuf>>
+where
+    F: Fn(&Path, bool) -> bool,
+{
+    // Directory -> whether it was accepted by `filter`
+    let mut dirs: HashMap<PathBuf, bool> = HashMap::new();
+    let mut files = Vec::new();
+    for file in git::ls_files(root)? {
+        let path = root.join(file);
+        // Tracked files deleted from the working tree, and submodules, which git lists as one entry
+        if !path.is_file() {
+            continue;
+        }
+        let accepted = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| *dir != root)
+            .all(|dir| {
+                *dirs
+                    .entry(dir.to_path_buf())
+                    .or_insert_with(|| filter(dir, true))
+            });
+        if accepted && filter(&path, false) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn discovery(respect_gitignore: bool, git_ls_files: bool) -> Discovery {
+        let mut discovery = Discovery::default();
+        discovery.respect_gitignore = respect_gitignore;
+        discovery.git_ls_files = git_ls_files;
+        discovery
+    }
+
+    /// Files below `tmp` accepted by `filter`, relative to it.
+    fn list(
+        tmp: &TempDir,
+        discovery: &Discovery,
+        filter: impl Fn(&Path, bool) -> bool + Sync,
+    ) -> Vec<String> {
+        files(&tmp.0, discovery, filter)
+            .unwrap()
+            .into_iter()
+            .map(|file| {
+                let file = file.strip_prefix(&tmp.0).unwrap();
+                file.to_string_lossy().replace('\\', "/")
+            })
+            .collect()
+    }
+
+    fn git(tmp: &TempDir, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(&tmp.0)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    /// Files for every case: ignored by `.ignore`, by `.gitignore`, hidden and in `target`.
+    fn write_files(tmp: &TempDir) {
+        tmp.write(".ignore", "ignored/\n");
+        tmp.write(".gitignore", "git-ignored/\n");
+        tmp.write("a/Cargo.toml", "");
+        tmp.write("a/src/lib.rs", "");
+        tmp.write(".hidden/Cargo.toml", "");
+        tmp.write("ignored/Cargo.toml", "");
+        tmp.write("git-ignored/Cargo.toml", "");
+        tmp.write("target/Cargo.toml", "");
+    }
+
+    fn manifests(path: &Path, is_dir: bool) -> bool {
+        if is_dir {
+            path.file_name().is_none_or(|name| name != "target")
+        } else {
+            path.file_name().is_some_and(|name| name == "Cargo.toml")
+        }
+    }
+
+    #[test]
+    fn walk_respects_ignore_files() {
+        let tmp = TempDir::new("walk-ignore");
+        write_files(&tmp);
+
+        // `.gitignore` only counts in a git checkout
+        assert_eq!(
+            list(&tmp, &discovery(true, false), manifests),
+            [
+                ".hidden/Cargo.toml",
+                "a/Cargo.toml",
+                "git-ignored/Cargo.toml"
+            ]
+        );
+        assert_eq!(
+            list(&tmp, &discovery(false, false), manifests),
+            [
+                ".hidden/Cargo.toml",
+                "a/Cargo.toml",
+                "git-ignored/Cargo.toml",
+                "ignored/Cargo.toml"
+            ]
+        );
+        // Rejected directories are skipped entirely
+        assert_eq!(
+            list(&tmp, &discovery(false, false), |path, is_dir| {
+                !is_dir || path.file_name().is_some_and(|name| name != "src")
+            }),
+            [
+                ".gitignore",
+                ".hidden/Cargo.toml",
+                ".ignore",
+                "a/Cargo.toml",
+                "git-ignored/Cargo.toml",
+                "ignored/Cargo.toml",
+                "target/Cargo.toml"
+            ]
+        );
+    }
+
+    #[test]
+    fn git_ls_files() {
+        let tmp = TempDir::new("walk-git");
+        write_files(&tmp);
+        tmp.write("deleted/Cargo.toml", "");
+        git(&tmp, &["init", "-q"]);
+        git(&tmp, &["add", "a", "deleted"]);
+        std::fs::remove_dir_all(tmp.0.join("deleted")).unwrap();
+
+        // Tracked and untracked files, but neither ignored nor deleted ones
+        let expected = [".hidden/Cargo.toml", "a/Cargo.toml", "ignored/Cargo.toml"];
+        assert_eq!(list(&tmp, &discovery(true, true), manifests), expected);
+        // Walking the directories finds the same, except that it also reads `.ignore`
+        assert_eq!(
+            list(&tmp, &discovery(true, false), manifests),
+            [".hidden/Cargo.toml", "a/Cargo.toml"]
+        );
+        assert!(
+            list(&tmp, &discovery(true, true), |_, _| true)
+                .iter()
+                .all(|file| !file.starts_with(".git/"))
+        );
+    }
+
+    #[test]
+    fn git_ls_files_outside_of_git_walks() {
+        let tmp = TempDir::new("walk-no-git");
+        write_files(&tmp);
+        assert_eq!(
+            list(&tmp, &discovery(true, true), manifests),
+            list(&tmp, &discovery(true, false), manifests)
+        );
+    }
+}
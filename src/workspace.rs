@@ -0,0 +1,318 @@
+//! Support for Cargo workspace inheritance (`foo.workspace = true`).
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+/// A field that may be inherited from the enclosing workspace.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, expecting = "expected a value or `{ workspace = true }`")]
+pub enum Inheritable<T> {
+    Value(T),
+    Inherited { workspace: bool },
+}
+
+impl<T: DeserializeOwned> Inheritable<T> {
+    /// Resolve the value, looking it up in `[workspace.package]` if inherited.
+    pub fn resolve(self, field: &str, workspace: Option<&Workspace>) -> Result<T> {
+        match self {
+            Inheritable::Value(value) => Ok(value),
+            Inheritable::Inherited { workspace: false } => {
+                Err(anyhow!("`{field}.workspace` must be `true`"))
+            }
+            Inheritable::Inherited { workspace: true } => {
+                let workspace = workspace.ok_or_else(|| {
+                    anyhow!("`{field}` is inherited but no workspace root was found")
+                })?;
+                let value = workspace.package.get(field).ok_or_else(|| {
+                    anyhow!(
+                        "`{field}` is inherited but not set in [workspace.package] of {}",
+                        workspace.manifest_path().display()
+                    )
+                })?;
+                Ok(value.clone().try_into()?)
+            }
+        }
+    }
+}
+
+/// The inheritable parts of a workspace root manifest.
+#[derive(Debug)]
+pub struct Workspace {
+    /// Directory containing the workspace root `Cargo.toml`.
+    pub root: PathBuf,
+    /// Contents of `[workspace.package]`.
+    pub package: toml::Table,
+    /// Contents of `[workspace.dependencies]`.
+    pub dependencies: toml::Table,
+    exclude: Vec<PathBuf>,
+}
+
+impl Workspace {
+    pub fn manifest_path(&self) -> PathBuf {
+        self.root.join("Cargo.toml")
+    }
+
+    fn excludes(&self, crate_dir: &Path) -> bool {
+        self.exclude.iter().any(|e| crate_dir.starts_with(e))
+    }
+
+    /// Resolve a `{ workspace = true }` dependency against `[workspace.dependencies]`.
+    ///
+    /// Dependencies that are not inherited are returned unchanged. Relative paths of
+    /// inherited dependencies are made absolute, since they are relative to the workspace root.
+    pub fn resolve_dependency(
+        workspace: Option<&Workspace>,
+        name: &str,
+        value: toml::Value,
+    ) -> Result<toml::Value> {
+        let Some(local) = value.as_table() else {
+            return Ok(value);
+        };
+        if local.get("workspace").and_then(|v| v.as_bool()) != Some(true) {
+            return Ok(value);
+        }
+
+        let workspace = workspace.ok_or_else(|| {
+            anyhow!("dependency `{name}` is inherited but no workspace root was found")
+        })?;
+        let mut resolved = match workspace.dependencies.get(name) {
+            Some(toml::Value::String(version)) => {
+                let mut table = toml::Table::new();
+                table.insert("version".into(), toml::Value::String(version.clone()));
+                table
+            }
+            Some(toml::Value::Table(table)) => table.clone(),
+            _ => {
+                return Err(anyhow!(
+                    "dependency `{name}` is inherited but not set in [workspace.dependencies] of {}",
+                    workspace.manifest_path().display()
+                ));
+            }
+        };
+
+        if let Some(toml::Value::String(path)) = resolved.get("path") {
+            let path = workspace.root.join(path).display().to_string();
+            resolved.insert("path".into(), toml::Value::String(path));
+        }
+
+        // Only `features` and `optional` may be specified next to `workspace = true`.
+        if let Some(toml::Value::Array(features)) = local.get("features") {
+            let mut all = match resolved.remove("features") {
+                Some(toml::Value::Array(base)) => base,
+                _ => Vec::new(),
+            };
+            all.extend(features.iter().cloned());
+            resolved.insert("features".into(), toml::Value::Array(all));
+        }
+        if let Some(optional) = local.get("optional") {
+            resolved.insert("optional".into(), optional.clone());
+        }
+
+        Ok(toml::Value::Table(resolved))
+    }
+}
+
+/// Locates and caches workspace roots for crates found during discovery.
+#[derive(Default)]
+pub struct Workspaces {
+    cache: HashMap<PathBuf, Option<Rc<Workspace>>>,
+}
+
+impl Workspaces {
+    /// Find the workspace the crate in `crate_dir` belongs to.
+    ///
+    /// `explicit` is the value of `package.workspace`, if set. Otherwise the crate directory
+    /// and its ancestors are searched for a manifest with a `[workspace]` table that does not
+    /// exclude the crate, like Cargo does.
+    pub fn find(
+        &mut self,
+        crate_dir: &Path,
+        explicit: Option<&str>,
+    ) -> Result<Option<Rc<Workspace>>> {
+        if let Some(explicit) = explicit {
+            let root = crate_dir.join(explicit);
+            return self
+                .load(&root)?
+                .map(Some)
+                .ok_or_else(|| anyhow!("{} is not a workspace root", root.display()));
+        }
+
+        for dir in crate_dir.ancestors() {
+            if let Some(workspace) = self.load(dir)?
+                && !workspace.excludes(crate_dir)
+            {
+                return Ok(Some(workspace));
+            }
+        }
+        Ok(None)
+    }
+
+    fn load(&mut self, dir: &Path) -> Result<Option<Rc<Workspace>>> {
+        if let Some(cached) = self.cache.get(dir) {
+            return Ok(cached.clone());
+        }
+
+        let manifest_path = dir.join("Cargo.toml");
+        let workspace = if manifest_path.is_file() {
+            let content = fs::read_to_string(&manifest_path)?;
+            let mut manifest: toml::Table = toml::from_str(&content)
+                .map_err(|e| anyhow!("Failed to parse {}: {}", manifest_path.display(), e))?;
+            match manifest.remove("workspace") {
+                Some(toml::Value::Table(mut ws)) => {
+                    let mut take_table = |key: &str| match ws.remove(key) {
+                        Some(toml::Value::Table(table)) => table,
+                        _ => toml::Table::new(),
+                    };
+                    let package = take_table("package");
+                    let dependencies = take_table("dependencies");
+                    let exclude = match ws.get("exclude") {
+                        Some(toml::Value::Array(exclude)) => exclude
+                            .iter()
+                            .filter_map(|e| e.as_str())
+                            .map(|e| dir.join(e))
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    Some(Rc::new(Workspace {
+                        root: dir.to_path_buf(),
+                        package,
+                        dependencies,
+                        exclude,
+                    }))
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        self.cache.insert(dir.to_path_buf(), workspace.clone());
+        Ok(workspace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const WORKSPACE: &str = r#"
+[workspace]
+members = ["embassy-*"]
+exclude = ["excluded"]
+
+[workspace.package]
+version = "0.3.0"
+publish = false
+
+[workspace.dependencies]
+embassy-b = { path = "embassy-b", version = "0.3.0", features = ["defmt"] }
+heapless = "0.8"
+"#;
+
+    #[test]
+    fn find_nearest_root() {
+        let tmp = TempDir::new("workspace-find");
+        tmp.write("Cargo.toml", WORKSPACE);
+        let crate_dir = tmp.0.join("embassy-a");
+
+        let mut workspaces = Workspaces::default();
+        let workspace = workspaces.find(&crate_dir, None).unwrap().unwrap();
+        assert_eq!(workspace.root, tmp.0);
+        assert!(
+            workspaces
+                .find(&tmp.0.join("excluded").join("foo"), None)
+                .unwrap()
+                .is_none_or(|w| w.root != tmp.0)
+        );
+    }
+
+    #[test]
+    fn find_explicit_root() {
+        let tmp = TempDir::new("workspace-explicit");
+        tmp.write("ws/Cargo.toml", WORKSPACE);
+        tmp.write("embassy-a/Cargo.toml", "[package]\nworkspace = \"../ws\"\n");
+        let crate_dir = tmp.0.join("embassy-a");
+
+        let mut workspaces = Workspaces::default();
+        let workspace = workspaces.find(&crate_dir, Some("../ws")).unwrap().unwrap();
+        assert!(workspace.manifest_path().ends_with("ws/Cargo.toml"));
+        assert!(workspaces.find(&crate_dir, Some("../nope")).is_err());
+    }
+
+    #[test]
+    fn resolve_inherited_fields() {
+        let tmp = TempDir::new("workspace-fields");
+        tmp.write("Cargo.toml", WORKSPACE);
+        let mut workspaces = Workspaces::default();
+        let workspace = workspaces.find(&tmp.0.join("embassy-a"), None).unwrap();
+        let workspace = workspace.as_deref();
+
+        let version: Inheritable<String> = Inheritable::Inherited { workspace: true };
+        assert_eq!(version.resolve("version", workspace).unwrap(), "0.3.0");
+        let publish: Inheritable<bool> = Inheritable::Inherited { workspace: true };
+        assert!(!publish.resolve("publish", workspace).unwrap());
+        let publish: Inheritable<bool> = Inheritable::Value(true);
+        assert!(publish.resolve("publish", workspace).unwrap());
+
+        let version: Inheritable<String> = Inheritable::Inherited { workspace: false };
+        assert!(version.resolve("version", workspace).is_err());
+        let version: Inheritable<String> = Inheritable::Inherited { workspace: true };
+        assert!(version.resolve("version", None).is_err());
+    }
+
+    #[test]
+    fn resolve_without_workspace_package() {
+        let tmp = TempDir::new("workspace-no-package");
+        tmp.write("Cargo.toml", "[workspace]\nmembers = [\"embassy-a\"]\n");
+        let mut workspaces = Workspaces::default();
+        let workspace = workspaces.find(&tmp.0.join("embassy-a"), None).unwrap();
+
+        let version: Inheritable<String> = Inheritable::Inherited { workspace: true };
+        let err = version
+            .resolve("version", workspace.as_deref())
+            .unwrap_err();
+        assert!(err.to_string().contains("[workspace.package]"), "{err}");
+    }
+
+    #[test]
+    fn resolve_inherited_dependencies() {
+        let tmp = TempDir::new("workspace-deps");
+        tmp.write("Cargo.toml", WORKSPACE);
+        let mut workspaces = Workspaces::default();
+        let workspace = workspaces.find(&tmp.0.join("embassy-a"), None).unwrap();
+        let workspace = workspace.as_deref();
+
+        let local: toml::Value =
+            toml::from_str("workspace = true\nfeatures = [\"log\"]\noptional = true").unwrap();
+        let resolved = Workspace::resolve_dependency(workspace, "embassy-b", local).unwrap();
+        assert_eq!(resolved["version"].as_str(), Some("0.3.0"));
+        assert_eq!(
+            resolved["path"].as_str().map(PathBuf::from),
+            Some(tmp.0.join("embassy-b"))
+        );
+        assert_eq!(
+            resolved["features"],
+            toml::Value::Array(vec!["defmt".into(), "log".into()])
+        );
+        assert_eq!(resolved["optional"].as_bool(), Some(true));
+
+        let local: toml::Value = toml::from_str("workspace = true").unwrap();
+        let resolved = Workspace::resolve_dependency(workspace, "heapless", local).unwrap();
+        assert_eq!(resolved["version"].as_str(), Some("0.8"));
+
+        let local: toml::Value = toml::from_str("workspace = true").unwrap();
+        assert!(Workspace::resolve_dependency(workspace, "missing", local).is_err());
+
+        // Dependencies that aren't inherited are passed through
+        let local = toml::Value::String("1.0".into());
+        let resolved = Workspace::resolve_dependency(None, "serde", local.clone()).unwrap();
+        assert_eq!(resolved, local);
+    }
+}